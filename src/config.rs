@@ -1,15 +1,16 @@
 use crate::exit_error;
-use crate::WatchDir;
+use crate::{NestedRoots, Pause, WatchDir, WatchPattern};
 use notify::{
-    event::{Event, EventKind},
+    event::{Event, EventKind, ModifyKind},
     Config as NotifyConfig, RecommendedWatcher, RecursiveMode, Result as NotifyResult, Watcher,
 };
-use std::fs;
-use std::io::BufReader;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::SystemTime;
 
-use serde_json::Deserializer;
+use humantime::format_rfc3339_millis;
 
 use hashbrown::{
     hash_map::HashMap,
@@ -18,168 +19,790 @@ use hashbrown::{
 
 use directory_trie::DirectoryTrie;
 
-use crate::CONFIG_DIR;
+use crate::schema::{ConfigDocument, GlobalSettings, CONFIG_VERSION};
+use crate::toml_config::{TomlConfig, TomlDir, TomlSettings};
+use crate::TimemHome;
 
 pub struct Config {
+    home: TimemHome,
     config_path: PathBuf,
+    toml_path: PathBuf,
+    lock_path: PathBuf,
+    /// Generation of the config.json contents currently loaded
+    generation: u64,
+    /// Modification time of config.json when it was last loaded or written, to notice hand edits,
+    /// which don't bump the generation
+    config_mtime: Option<SystemTime>,
+    settings: GlobalSettings,
     watched_dirs: HashMap<PathBuf, WatchDir>,
+    /// Config entries that could not be loaded. They are kept (and written back) so they can
+    /// recover once the problem is fixed
+    unavailable_dirs: HashMap<PathBuf, UnavailableDir>,
+    /// Glob patterns whose matching directories are watched automatically
+    patterns: Vec<WatchPattern>,
     pub dirs_with_changes: HashSet<PathBuf>,
-    dir_trie: DirectoryTrie<PathBuf>,
+    dir_trie: DirectoryTrie<TrieEntry>,
     dir_watcher: RecommendedWatcher,
+    file_watcher: FileWatcher,
+    pattern_watcher: PatternWatcher,
     config_change_listener: Receiver<NotifyResult<Event>>,
     is_watching_changes: bool,
+    /// Why config.json failed to load when this config was created, if it did
+    load_error: Option<String>,
+    /// Loaded by `load_read_only`, so it must not be written back
+    read_only: bool,
 }
 
 impl Config {
-    pub fn new(should_watch_changes: bool) -> Result<Self, String> {
-        let mut config_path = match *CONFIG_DIR {
-            Some(ref dir) => dir.clone(),
-            None => {
-                return Err("Could not locate OS config directory".into());
-            }
-        };
-        config_path.push("config.json");
+    pub fn new(home: &TimemHome, should_watch_changes: bool) -> Result<Self, String> {
+        // Exclusive, as loading may migrate config.json to the current schema
+        let mut lock = ConfigLock::exclusive(&home.config_lock_path())?;
+        let (mut config, stored_version) =
+            Self::load(home, should_watch_changes, lock.generation())?;
+        if stored_version < CONFIG_VERSION {
+            config.migrate(stored_version, &mut lock)?;
+        }
+        Ok(config)
+    }
 
-        let watched_dirs = if config_path.exists() {
+    /// Loads config.json for inspection only: no lock is taken, an older schema is migrated in
+    /// memory only, and writing it back is refused
+    pub fn load_read_only(home: &TimemHome) -> Result<Self, String> {
+        let (mut config, _) = Self::load(home, false, 0)?;
+        config.read_only = true;
+        Ok(config)
+    }
+
+    /// Loads config.json, returning the config and the schema version it was stored as
+    fn load(
+        home: &TimemHome,
+        should_watch_changes: bool,
+        generation: u64,
+    ) -> Result<(Self, u32), String> {
+        let config_path = home.config_path();
+        let config_dir = home.root().to_owned();
+        let lock_path = home.config_lock_path();
+        let toml_path = home.toml_path();
+
+        let config_mtime = modified_time(&config_path);
+        let mut load_error = None;
+        let LoadedConfig {
+            settings,
+            mut watched_dirs,
+            mut unavailable_dirs,
+            patterns,
+            stored_version,
+        } = if config_path.exists() {
             match Self::load_config(&config_path) {
-                Ok(dirs) => dirs,
+                Ok(loaded) => loaded,
                 Err(err) => {
-                    log::debug!("Failed to load config: {}", err);
-                    HashMap::new()
+                    log::error!("Failed to load config: {}", err);
+                    load_error = Some(err);
+                    LoadedConfig::default()
                 }
             }
         } else {
-            fs::create_dir_all(
-                config_path
-                    .parent()
-                    .ok_or("Could not get parent directory of config file".to_string())?,
-            )
-            .map_err(|err| err.to_string())?;
-            HashMap::new()
+            LoadedConfig::default()
         };
 
-        let mut dir_trie = DirectoryTrie::new();
-        watched_dirs
-            .iter()
-            .for_each(|(path, _)| dir_trie.insert(path, path.clone()));
-
         let (tx, rx) = mpsc::channel();
+        let mut file_watcher = FileWatcher::new(tx.clone())?;
+        let mut pattern_watcher = PatternWatcher::new(tx.clone())?;
         let mut dir_watcher: RecommendedWatcher =
             Watcher::new(tx, NotifyConfig::default()).map_err(|err| err.to_string())?;
 
+        // Watch the directory rather than the file itself, as config.json is replaced by a rename
+        // on every write
         dir_watcher
-            .watch(&config_path, RecursiveMode::Recursive)
+            .watch(&config_dir, RecursiveMode::NonRecursive)
             .map_err(|err| err.to_string())?;
 
         if should_watch_changes {
-            for (path, _) in watched_dirs.iter() {
-                dir_watcher
-                    .watch(&path, RecursiveMode::Recursive)
-                    .map_err(|err| err.to_string())?;
+            pattern_watcher.update(&patterns);
+            let unwatchable: Vec<(PathBuf, String)> = watched_dirs
+                .iter()
+                .filter_map(|(path, watch_dir)| {
+                    let result = if watch_dir.is_file() {
+                        file_watcher.watch(path)
+                    } else {
+                        watch_dir
+                            .roots()
+                            .iter()
+                            .try_for_each(|root| dir_watcher.watch(root, RecursiveMode::Recursive))
+                    };
+                    result.err().map(|err| (path.clone(), err.to_string()))
+                })
+                .collect();
+            for (path, reason) in unwatchable {
+                log::error!(
+                    "Failed to register notify handler on dir {:?}: {reason}",
+                    path
+                );
+                if let Some(watch_dir) = watched_dirs.remove(&path) {
+                    unavailable_dirs
+                        .insert(path, UnavailableDir::from_watch_dir(&watch_dir, reason));
+                }
             }
         }
 
-        Ok(Self {
+        let mut config = Self {
+            home: home.clone(),
             config_path,
+            toml_path,
+            lock_path,
+            generation,
+            config_mtime,
+            settings,
             watched_dirs,
-            dir_trie,
+            unavailable_dirs,
+            patterns,
+            dir_trie: DirectoryTrie::new(),
             dir_watcher,
+            file_watcher,
+            pattern_watcher,
             config_change_listener: rx,
             is_watching_changes: should_watch_changes,
             dirs_with_changes: HashSet::new(),
-        })
+            load_error,
+            read_only: false,
+        };
+        config.update_nesting();
+        Ok((config, stored_version))
+    }
+
+    /// Backs up config.json as `config.json.v<version>.bak`, then rewrites it in the current
+    /// schema. Must be called with the exclusive config lock held
+    fn migrate(&mut self, stored_version: u32, lock: &mut ConfigLock) -> Result<(), String> {
+        let backup_path = self
+            .config_path
+            .with_extension(format!("json.v{stored_version}.bak"));
+        fs::copy(&self.config_path, &backup_path).map_err(|err| err.to_string())?;
+        self.write_config(lock)?;
+        log::info!(
+            "Migrated config.json from schema version {stored_version} to {CONFIG_VERSION}, backup at {:?}",
+            backup_path
+        );
+        Ok(())
+    }
+
+    /// Loads config.json entry by entry. Entries that fail to load (missing target or dotgit
+    /// directory, invalid settings) are returned as unavailable instead of failing the whole file
+    fn load_config<P: AsRef<Path>>(path: P) -> Result<LoadedConfig, String> {
+        let config_content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.to_string()),
+        };
+        let (document, stored_version) = ConfigDocument::parse(&config_content)?;
+
+        let mut loaded = LoadedConfig {
+            stored_version,
+            ..Default::default()
+        };
+        for (index, value) in document.dirs.into_iter().enumerate() {
+            let path = value
+                .get("target_dir")
+                .and_then(serde_json::Value::as_str)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(format!("<config entry {index}>")));
+            // Fails with the reason if the watched file or directory no longer exists
+            let watch_dir = serde_json::from_value::<WatchDir>(value.clone())
+                .map_err(|err| err.to_string())
+                .and_then(|mut watch_dir| {
+                    watch_dir
+                        .set_defaults(&document.settings)
+                        .map_err(|err| err.to_string())?;
+                    Ok(watch_dir)
+                });
+            match watch_dir {
+                Ok(watch_dir) => {
+                    loaded.watched_dirs.insert(path, watch_dir);
+                }
+                Err(reason) => {
+                    log::warn!("Watched dir {:?} is unavailable: {reason}", path);
+                    loaded.unavailable_dirs.insert(
+                        path,
+                        UnavailableDir {
+                            config: value,
+                            reason,
+                        },
+                    );
+                }
+            }
+        }
+        loaded.settings = document.settings;
+        loaded.patterns = document.patterns;
+        Ok(loaded)
+    }
+
+    /// Writes the in-memory watch list to config.json, overwriting whatever is on disk. Prefer
+    /// [`Config::modify`] for read-modify-write updates
+    pub fn flush_config(&mut self) -> Result<(), String> {
+        let mut lock = ConfigLock::exclusive(&self.lock_path)?;
+        self.write_config(&mut lock)
+    }
+
+    /// Reloads the watch list from disk, applies `modify` and writes the result back, all while
+    /// holding the config lock, so concurrent writers can't drop each other's changes. Nothing is
+    /// written if `modify` fails
+    pub fn modify<T, F>(&mut self, modify: F) -> Result<T, String>
+    where
+        F: FnOnce(&mut Self) -> Result<T, String>,
+    {
+        let mut lock = ConfigLock::exclusive(&self.lock_path)?;
+        let loaded = Self::load_config(&self.config_path)?;
+        self.apply_loaded_dirs(loaded);
+        let result = modify(self)?;
+        self.write_config(&mut lock)?;
+        Ok(result)
+    }
+
+    /// Copies config.json to a timestamped file in the backups directory. Returns the backup's
+    /// path, or `None` if there are no directories to back up
+    pub fn backup(&self) -> Result<Option<PathBuf>, String> {
+        let is_empty = self.watched_dirs.is_empty()
+            && self.unavailable_dirs.is_empty()
+            && self.patterns.is_empty();
+        if is_empty || !self.config_path.exists() {
+            return Ok(None);
+        }
+        let backups_dir = self.home.backups_dir();
+        fs::create_dir_all(&backups_dir).map_err(|err| err.to_string())?;
+        let backup_path = backups_dir.join(format!(
+            "config-{}.json",
+            format_rfc3339_millis(SystemTime::now())
+        ));
+        fs::copy(&self.config_path, &backup_path).map_err(|err| err.to_string())?;
+        Ok(Some(backup_path))
+    }
+
+    /// Returns the paths of the config.json backups, oldest first
+    pub fn list_backups(home: &TimemHome) -> Result<Vec<PathBuf>, String> {
+        let entries = match fs::read_dir(home.backups_dir()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.to_string()),
+        };
+        let mut backups: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        // The names embed an RFC 3339 timestamp, so they sort chronologically
+        backups.sort();
+        Ok(backups)
     }
 
-    fn load_config<P: AsRef<Path>>(path: P) -> Result<HashMap<PathBuf, WatchDir>, String> {
-        let config_content = fs::read_to_string(path).map_err(|err| err.to_string())?;
-        // TODO: The following line fails if the config file has a watched directory which no
-        // longer exists. Fix that
-        let dirs: HashMap<PathBuf, WatchDir> =
-            serde_json::from_str(&config_content).map_err(|err| err.to_string())?;
-        Ok(dirs)
+    /// Removes every watched directory and watch pattern and resets the global settings.
+    /// Snapshot repos are left on disk
+    pub fn clear(&mut self) {
+        self.apply_loaded_dirs(LoadedConfig::default());
     }
 
-    pub fn flush_config(&self) -> Result<(), String> {
-        let content = serde_json::to_string(&self.watched_dirs).map_err(|err| err.to_string())?;
-        fs::write(&self.config_path, content).map_err(|err| err.to_string())?;
+    /// Replaces the watched directories and global settings with those of a config.json backup
+    pub fn restore_backup<P: AsRef<Path>>(&mut self, backup_path: P) -> Result<(), String> {
+        let loaded = Self::load_config(backup_path)?;
+        self.apply_loaded_dirs(loaded);
         Ok(())
     }
 
-    pub fn add_watched_dir(&mut self, watch_dir_conf: WatchDir) {
-        let path = watch_dir_conf.target_dir().to_owned();
-        if self
+    /// Applies config.toml, if there is one, and persists the result to config.json
+    pub fn sync_toml(&mut self) -> Result<(), String> {
+        if !self.toml_path.exists() {
+            return Ok(());
+        }
+        let toml = TomlConfig::load(&self.toml_path)?;
+        for problem in self.modify(|config| config.import_toml(&toml))? {
+            log::error!("config.toml: {problem}");
+        }
+        log::info!("Applied {:?}", self.toml_path);
+        Ok(())
+    }
+
+    /// Replaces the global settings with the TOML defaults, then creates or updates every
+    /// directory it declares. Watched directories it doesn't mention are left alone. Returns a
+    /// description of every directory entry that could not be applied
+    pub fn import_toml(&mut self, toml: &TomlConfig) -> Result<Vec<String>, String> {
+        let mut settings = toml.defaults.to_global_settings()?;
+        // The global pause is runtime state, not part of the TOML config
+        settings.pause = self.settings.pause;
+        self.set_settings(settings);
+
+        Ok(toml
+            .dirs
+            .iter()
+            .filter_map(|dir| {
+                self.import_toml_dir(dir)
+                    .err()
+                    .map(|err| format!("{}: {err}", dir.path))
+            })
+            .collect())
+    }
+
+    /// Pauses (or with `None`, resumes) every watched directory
+    pub fn set_global_pause(&mut self, pause: Option<Pause>) {
+        let mut settings = self.settings.clone();
+        settings.pause = pause;
+        self.set_settings(settings);
+    }
+
+    fn set_settings(&mut self, settings: GlobalSettings) {
+        self.settings = settings;
+        for watch_dir in self.watched_dirs.values_mut() {
+            if let Err(e) = watch_dir.set_defaults(&self.settings) {
+                log::error!(
+                    "Failed to apply global settings to {:?}: {e}",
+                    watch_dir.target_dir()
+                );
+            }
+        }
+        self.update_nesting();
+    }
+
+    /// Policy for watched entries nested in another watched directory
+    pub fn nested_roots(&self) -> NestedRoots {
+        self.settings.nested_roots.unwrap_or_default()
+    }
+
+    /// Rebuilds the directory trie and, under the `exclude` policy, tells every watched entry
+    /// which nested ones its snapshots must leave out
+    fn update_nesting(&mut self) {
+        self.dir_trie = build_trie(&self.watched_dirs);
+        let policy = self.nested_roots();
+        let all_roots: Vec<(PathBuf, PathBuf)> = self
             .watched_dirs
-            .insert(path.to_owned(), watch_dir_conf)
-            .is_none()
-            && self.is_watching_changes
-        {
-            // Inserted for the first time, add to watch list
-            match self.dir_watcher.watch(&path, RecursiveMode::Recursive) {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!("Failed to register notify handler on dir {:?}: {e}", path);
+            .iter()
+            .flat_map(|(path, watch_dir)| {
+                watch_dir
+                    .roots()
+                    .into_iter()
+                    .map(move |root| (path.clone(), root))
+            })
+            .collect();
+        for (path, watch_dir) in self.watched_dirs.iter_mut() {
+            let nested = match policy {
+                NestedRoots::Include => Vec::new(),
+                NestedRoots::Exclude => {
+                    let roots = watch_dir.roots();
+                    all_roots
+                        .iter()
+                        .filter(|(other, root)| other != path && is_nested(root, &roots))
+                        .map(|(_, root)| root.clone())
+                        .collect()
+                }
+            };
+            watch_dir.set_nested_roots(nested);
+        }
+    }
+
+    /// Returns the keys of the watched entries a change at `path` belongs to: the most specific
+    /// one, and under the `include` policy every watched directory containing it as well
+    fn owners_of(&self, path: &Path) -> Vec<PathBuf> {
+        let mut owners = Vec::new();
+        let mut entry = self.dir_trie.get(path);
+        while let Some(found) = entry {
+            entry = match self.nested_roots() {
+                NestedRoots::Include => found
+                    .root
+                    .parent()
+                    .and_then(|parent| self.dir_trie.get(parent)),
+                NestedRoots::Exclude => None,
+            };
+            owners.push(found.key);
+        }
+        owners
+    }
+
+    /// Returns the other watched entries overlapping the one keyed by `path`, each with whether
+    /// it is nested in that one (rather than containing it)
+    pub fn overlapping<P: AsRef<Path>>(&self, path: P) -> Vec<(&WatchDir, bool)> {
+        let Some(watch_dir) = self.watched_dirs.get(path.as_ref()) else {
+            return Vec::new();
+        };
+        let roots = watch_dir.roots();
+        self.watched_dirs
+            .iter()
+            .filter(|(other, _)| other.as_path() != path.as_ref())
+            .filter_map(|(_, other)| {
+                let other_roots = other.roots();
+                if other_roots.iter().any(|root| is_nested(root, &roots)) {
+                    Some((other, true))
+                } else if roots.iter().any(|root| is_nested(root, &other_roots)) {
+                    Some((other, false))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn import_toml_dir(&mut self, dir: &TomlDir) -> Result<(), String> {
+        let path = dir
+            .expanded_path()?
+            .canonicalize()
+            .map_err(|err| err.to_string())?;
+        if !path.is_dir() && !path.is_file() {
+            return Err("Path is not a directory or a file".into());
+        }
+
+        let overrides = dir.overrides.to_global_settings()?;
+        if overrides.nested_roots.is_some() {
+            return Err("nested_roots can only be set in [defaults]".into());
+        }
+        let frequency = overrides
+            .frequency
+            .or(self.settings.frequency)
+            .ok_or("No frequency set for the directory or in [defaults]")?;
+        let max_file_size = overrides
+            .max_file_size
+            .or(self.settings.max_file_size)
+            .unwrap_or(0);
+
+        let mut new_watch_dir = None;
+        let watch_dir = match self.watched_dirs.get_mut(&path) {
+            Some(watch_dir) => watch_dir,
+            None => new_watch_dir.insert(
+                WatchDir::new(&self.home, path, frequency, max_file_size)
+                    .map_err(|err| err.to_string())?,
+            ),
+        };
+        watch_dir.set_frequency(frequency);
+        watch_dir.set_max_file_size(max_file_size);
+        watch_dir
+            .set_ignores(overrides.ignores)
+            .map_err(|err| err.to_string())?;
+        watch_dir.set_retention(overrides.retention);
+        watch_dir.set_identity(overrides.identity);
+
+        if let Some(watch_dir) = new_watch_dir {
+            self.add_watched_dir(watch_dir);
+        }
+        Ok(())
+    }
+
+    /// Returns the global settings and watched directories in TOML form. Unavailable
+    /// directories, groups and watch patterns are left out
+    pub fn export_toml(&self) -> TomlConfig {
+        let mut dirs: Vec<&WatchDir> = self
+            .watched_dirs
+            .values()
+            .filter(|watch_dir| watch_dir.group().is_none())
+            .collect();
+        dirs.sort_by(|left, right| left.target_dir().cmp(right.target_dir()));
+        TomlConfig {
+            defaults: TomlSettings::from_global_settings(&self.settings),
+            dirs: dirs.into_iter().map(TomlDir::from_watch_dir).collect(),
+        }
+    }
+
+    /// Writes config.json atomically (temp file and rename) and bumps the generation. Must be
+    /// called with the exclusive config lock held
+    fn write_config(&mut self, lock: &mut ConfigLock) -> Result<(), String> {
+        if self.read_only {
+            return Err("Config was loaded read-only".into());
+        }
+        let mut entries: Vec<(&PathBuf, serde_json::Value)> = Vec::new();
+        for (path, watch_dir) in self.watched_dirs.iter() {
+            entries.push((
+                path,
+                serde_json::to_value(watch_dir).map_err(|err| err.to_string())?,
+            ));
+        }
+        for (path, unavailable) in self.unavailable_dirs.iter() {
+            entries.push((path, unavailable.config.clone()));
+        }
+        entries.sort_by_key(|(path, _)| *path);
+
+        let document = ConfigDocument {
+            version: CONFIG_VERSION,
+            settings: self.settings.clone(),
+            dirs: entries.into_iter().map(|(_, value)| value).collect(),
+            patterns: self.patterns.clone(),
+        };
+        let content = serde_json::to_string_pretty(&document).map_err(|err| err.to_string())?;
+        write_atomically(&self.config_path, &content)?;
+        self.config_mtime = modified_time(&self.config_path);
+
+        self.generation = lock.generation() + 1;
+        lock.set_generation(self.generation)
+    }
+
+    /// Replaces the watch list with `dirs`, registering notify handlers for new directories and
+    /// dropping the ones no longer present
+    fn apply_loaded_dirs(&mut self, loaded: LoadedConfig) {
+        let LoadedConfig {
+            settings,
+            watched_dirs: dirs,
+            unavailable_dirs,
+            patterns,
+            ..
+        } = loaded;
+        self.settings = settings;
+        self.unavailable_dirs = unavailable_dirs;
+        self.patterns = patterns;
+        if self.is_watching_changes {
+            self.pattern_watcher.update(&self.patterns);
+        }
+
+        let removed: Vec<PathBuf> = self
+            .watched_dirs
+            .keys()
+            .filter(|path| !dirs.contains_key(*path))
+            .cloned()
+            .collect();
+        for path in removed {
+            self.remove_watched_dir(&path);
+            log::info!("Config file changed. Removed watched dir {:?}", path);
+        }
+
+        for (path, watch_dir) in dirs {
+            if self.insert_watched_dir(watch_dir) {
+                log::info!("Config file changed. Added new watched dir {:?}", path);
+            }
+        }
+
+        self.update_nesting();
+    }
+
+    /// Reloads config.json if another process wrote a new generation of it, or it was edited by
+    /// hand since it was last loaded
+    fn reload_if_newer(&mut self) -> Result<(), String> {
+        let mut lock = ConfigLock::shared(&self.lock_path)?;
+        let generation = lock.generation();
+        let config_mtime = modified_time(&self.config_path);
+        if generation == self.generation && config_mtime == self.config_mtime {
+            return Ok(());
+        }
+
+        let loaded = Self::load_config(&self.config_path)?;
+        drop(lock);
+        self.apply_loaded_dirs(loaded);
+        self.generation = generation;
+        self.config_mtime = config_mtime;
+        log::debug!("Loaded config generation {generation}");
+        Ok(())
+    }
+
+    pub fn add_watched_dir(&mut self, mut watch_dir_conf: WatchDir) {
+        if let Err(e) = watch_dir_conf.set_defaults(&self.settings) {
+            log::error!(
+                "Failed to apply global settings to {:?}: {e}",
+                watch_dir_conf.target_dir()
+            );
+        }
+        let path = watch_dir_conf.target_dir().to_owned();
+        self.unavailable_dirs.remove(&path);
+        self.insert_watched_dir(watch_dir_conf);
+        self.update_nesting();
+    }
+
+    /// Adds a watch pattern, replacing the one with the same pattern if any, and watches the
+    /// directories already matching it. Returns the newly watched directories
+    pub fn add_watch_pattern(&mut self, pattern: WatchPattern) -> Vec<PathBuf> {
+        self.patterns
+            .retain(|existing| existing.pattern() != pattern.pattern());
+        self.patterns.push(pattern);
+        if self.is_watching_changes {
+            self.pattern_watcher.update(&self.patterns);
+        }
+        self.add_pattern_matches()
+    }
+
+    /// Watches the directories matching a watch pattern that aren't watched yet and persists
+    /// them to config.json. They are marked as changed, as files may have been added before
+    /// they were watched. Returns the newly watched directories
+    pub fn watch_pattern_matches(&mut self) -> Result<Vec<PathBuf>, String> {
+        if self.unwatched_pattern_matches().is_empty() {
+            return Ok(Vec::new());
+        }
+        let added = self.modify(|config| Ok(config.add_pattern_matches()))?;
+        for path in added.iter() {
+            log::info!("Watching {:?}, which matches a watch pattern", path);
+            self.dirs_with_changes.insert(path.clone());
+        }
+        Ok(added)
+    }
+
+    fn add_pattern_matches(&mut self) -> Vec<PathBuf> {
+        let mut added = Vec::new();
+        for (path, pattern) in self.unwatched_pattern_matches() {
+            match WatchDir::new(
+                &self.home,
+                path.clone(),
+                pattern.frequency(),
+                pattern.max_file_size(),
+            ) {
+                Ok(watch_dir) => {
+                    self.add_watched_dir(watch_dir);
+                    added.push(path);
                 }
+                Err(err) => log::error!("Failed to watch {:?}: {err}", path),
+            }
+        }
+        added
+    }
+
+    /// Returns the directories matching a watch pattern that are neither watched (on their own
+    /// or in a group) nor unavailable, with the first pattern they match
+    fn unwatched_pattern_matches(&self) -> Vec<(PathBuf, WatchPattern)> {
+        let mut matches: Vec<(PathBuf, WatchPattern)> = Vec::new();
+        for pattern in self.patterns.iter() {
+            let paths = match pattern.find_matches() {
+                Ok(paths) => paths,
+                Err(err) => {
+                    log::error!("Failed to expand {}: {err}", pattern.pattern());
+                    continue;
+                }
+            };
+            for path in paths {
+                let path = path.canonicalize().unwrap_or(path);
+                let known = self.watched_dirs.contains_key(&self.resolve_target(&path))
+                    || self.unavailable_dirs.contains_key(&path)
+                    || matches.iter().any(|(matched, _)| matched == &path);
+                if !known {
+                    matches.push((path, pattern.clone()));
+                }
+            }
+        }
+        matches
+    }
+
+    pub fn iter_watch_patterns(&self) -> impl Iterator<Item = &WatchPattern> {
+        self.patterns.iter()
+    }
+
+    /// Inserts `watch_dir` into the watch list, registering notify handlers for the paths it
+    /// snapshots unless they are registered already. Returns whether it wasn't watched before
+    fn insert_watched_dir(&mut self, watch_dir: WatchDir) -> bool {
+        let path = watch_dir.target_dir().to_owned();
+        let roots = watch_dir.roots();
+        let is_file = watch_dir.is_file();
+        let previous = self.watched_dirs.insert(path.clone(), watch_dir);
+        if !self.is_watching_changes {
+            return previous.is_none();
+        }
+
+        match previous {
+            // The roots of a group can change without its key changing
+            Some(ref previous) if previous.roots() != roots || previous.is_file() != is_file => {
+                if let Err(e) = self.unwatch_target(&previous.roots(), previous.is_file()) {
+                    log::debug!("Failed to unregister notify handler on dir {:?}: {e}", path);
+                }
+            }
+            Some(_) => return false,
+            None => {}
+        }
+        // Inserted for the first time, add to watch list
+        if let Err(e) = self.watch_target(&roots, is_file) {
+            log::error!("Failed to register notify handler on dir {:?}: {e}", path);
+        }
+        previous.is_none()
+    }
+
+    /// Removes the watched directory, file or group keyed by `path` from the watch list
+    pub fn remove_watched_dir<P: AsRef<Path>>(&mut self, path: P) -> Option<WatchDir> {
+        let path = path.as_ref();
+        let watch_dir = self.watched_dirs.remove(path)?;
+        self.dirs_with_changes.remove(path);
+        if self.is_watching_changes {
+            if let Err(e) = self.unwatch_target(&watch_dir.roots(), watch_dir.is_file()) {
+                log::debug!("Failed to unregister notify handler on dir {:?}: {e}", path);
+            }
+        }
+        self.update_nesting();
+        Some(watch_dir)
+    }
+
+    /// Unregisters the notify handlers of all watched directories. Changes already recorded in
+    /// `dirs_with_changes` are kept
+    pub fn stop_watching_changes(&mut self) {
+        if !self.is_watching_changes {
+            return;
+        }
+        let targets: Vec<(Vec<PathBuf>, bool)> = self
+            .watched_dirs
+            .values()
+            .map(|watch_dir| (watch_dir.roots(), watch_dir.is_file()))
+            .collect();
+        for (roots, is_file) in targets {
+            if let Err(e) = self.unwatch_target(&roots, is_file) {
+                log::debug!(
+                    "Failed to unregister notify handler on dir {:?}: {e}",
+                    roots
+                );
+            }
+        }
+        self.pattern_watcher.update(&[]);
+        self.is_watching_changes = false;
+    }
+
+    /// Registers notify handlers for the paths a watched directory, file or group snapshots
+    fn watch_target(&mut self, roots: &[PathBuf], is_file: bool) -> NotifyResult<()> {
+        for root in roots {
+            if is_file {
+                self.file_watcher.watch(root)?;
+            } else {
+                self.dir_watcher.watch(root, RecursiveMode::Recursive)?;
             }
         }
+        Ok(())
+    }
+
+    fn unwatch_target(&mut self, roots: &[PathBuf], is_file: bool) -> NotifyResult<()> {
+        for root in roots {
+            if is_file {
+                self.file_watcher.unwatch(root)?;
+            } else {
+                self.dir_watcher.unwatch(root)?;
+            }
+        }
+        Ok(())
     }
 
     pub fn update_if_changed(&mut self) -> Result<(), String> {
-        // TODO: Change the following line so we don't (semantically) recompute every function call
-        let config_file_path: PathBuf = CONFIG_DIR
-            .clone()
-            .and_then(|f| Some(f.join("config.json")))
-            .ok_or("Could not locate config directory")?;
+        let mut config_changed = false;
+        let mut toml_changed = false;
+        let mut pattern_matched = false;
         loop {
             match self.config_change_listener.try_recv() {
                 Ok(event_result) => {
                     if let Ok(event) = event_result {
-                        let path = event.paths.get(0).ok_or("Got notify event without path")?;
-
-                        if !matches!(event.kind, EventKind::Access(_)) {
-                            // TODO: Determine if the config.json file was changed or some other watched file
-                            if path == &config_file_path {
-                                // Config file was changed
-                                let file = fs::File::open(&self.config_path)
-                                    .map_err(|err| err.to_string())?;
-                                let buf_reader = BufReader::new(file);
-                                let serde_stream = Deserializer::from_reader(buf_reader)
-                                    .into_iter::<serde_json::Value>();
-                                for watch_dir_res in serde_stream {
-                                    let serde_value =
-                                        watch_dir_res.map_err(|err| err.to_string())?;
-                                    if let serde_json::Value::Object(map) = serde_value {
-                                        let (_, watch_dir_value) = map.into_iter().next().ok_or("Invalid config.json contents. Try clearing config with `timemctl clearconf`".to_string())?;
-                                        let watch_dir: WatchDir =
-                                            serde_json::from_value(watch_dir_value)
-                                                .map_err(|err| err.to_string())?;
-                                        let path = watch_dir.target_dir().to_owned();
-                                        if self
-                                            .watched_dirs
-                                            .insert(path.clone(), watch_dir)
-                                            .is_none()
-                                        {
-                                            // This is a new dir to watch!
-                                            self.dir_watcher
-                                                .watch(&path, RecursiveMode::Recursive)
-                                                .map_err(|err| err.to_string())?;
-                                            self.dir_trie.insert(&path, path.clone());
-                                            log::info!(
-                                                "Config file changed. Added new watched dir {:?}",
-                                                path
-                                            );
-                                        }
-                                    }
-                                }
-                            } else {
-                                // Some other watched directory file was changed. Add to the hash set
-                                if let Some(parent_dir) = self.dir_trie.get(path) {
-                                    log::trace!(
-                                        "Observed change with path {:?}, found watched path {:?}",
-                                        path,
-                                        &parent_dir
-                                    );
-                                    self.dirs_with_changes.insert(parent_dir);
-                                }
+                        if matches!(event.kind, EventKind::Access(_)) {
+                            continue;
+                        }
+
+                        if event.paths.iter().any(|path| path == &self.config_path) {
+                            config_changed = true;
+                            continue;
+                        }
+                        if event.paths.iter().any(|path| path == &self.toml_path) {
+                            toml_changed = true;
+                            continue;
+                        }
+
+                        // Some other watched directory file was changed. Add to the hash set. All
+                        // paths are checked, as a file renamed over a watched file is only
+                        // reported under its new name as the second path
+                        if event.paths.is_empty() {
+                            return Err("Got notify event without path".into());
+                        }
+                        let created = matches!(
+                            event.kind,
+                            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+                        );
+                        if created && !pattern_matched {
+                            pattern_matched = event.paths.iter().any(|path| {
+                                self.patterns.iter().any(|pattern| pattern.matches(path))
+                            });
+                        }
+                        for path in event.paths.iter() {
+                            for owner in self.owners_of(path) {
+                                log::trace!(
+                                    "Observed change with path {:?}, found watched path {:?}",
+                                    path,
+                                    &owner
+                                );
+                                self.dirs_with_changes.insert(owner);
                             }
                         }
                     }
@@ -193,9 +816,36 @@ impl Config {
                 }
             }
         }
+
+        if toml_changed {
+            // Also picks up any newer config.json
+            self.sync_toml()?;
+        } else if config_changed {
+            self.reload_if_newer()?;
+        }
+        if pattern_matched {
+            self.watch_pattern_matches()?;
+        }
         Ok(())
     }
 
+    /// Compares every watched directory's working tree with its HEAD tree and marks the dirty ones
+    /// as changed, so edits made while no events were being delivered (daemon stopped, system
+    /// suspended) are not missed. Walks and stages every watched tree (see
+    /// `WatchDir::has_pending_changes`), so this can take a while on large trees
+    pub fn mark_dirty_dirs(&mut self) {
+        for (path, watch_dir) in self.watched_dirs.iter() {
+            match watch_dir.has_pending_changes() {
+                Ok(true) => {
+                    log::info!("Found unsnapshotted changes in {:?}", path);
+                    self.dirs_with_changes.insert(path.clone());
+                }
+                Ok(false) => {}
+                Err(err) => log::error!("Failed to scan {:?} for changes: {err}", path),
+            }
+        }
+    }
+
     pub fn iter_changed_paths(&self) -> HashBrownSetIter<PathBuf> {
         self.dirs_with_changes.iter()
     }
@@ -205,7 +855,356 @@ impl Config {
         self.watched_dirs.get(path)
     }
 
+    pub fn get_watched_dir_mut<P: AsRef<Path>>(&mut self, path: P) -> Option<&mut WatchDir> {
+        self.watched_dirs.get_mut(path.as_ref())
+    }
+
+    /// Returns the key of the watched entry `target` refers to: the name of a group, a path
+    /// watched on its own, or one of the roots of a group. Other paths are returned canonicalized
+    /// if possible
+    pub fn resolve_target<P: AsRef<Path>>(&self, target: P) -> PathBuf {
+        let target = target.as_ref();
+        let groups = || {
+            self.watched_dirs
+                .values()
+                .filter(|watch_dir| watch_dir.group().is_some())
+        };
+        if let Some(group) = groups().find(|group| group.group().map(Path::new) == Some(target)) {
+            return group.target_dir().to_owned();
+        }
+
+        let path = target.canonicalize().unwrap_or_else(|_| target.to_owned());
+        groups()
+            .find(|group| group.roots().contains(&path))
+            .map(|group| group.target_dir().to_owned())
+            .unwrap_or(path)
+    }
+
     pub fn iter_watched_dirs(&self) -> impl Iterator<Item = &WatchDir> {
         self.watched_dirs.values()
     }
+
+    pub fn iter_watched_dirs_mut(&mut self) -> impl Iterator<Item = &mut WatchDir> {
+        self.watched_dirs.values_mut()
+    }
+
+    /// Returns why config.json could not be loaded, in which case the config started out empty
+    pub fn load_error(&self) -> Option<&str> {
+        self.load_error.as_deref()
+    }
+
+    pub fn home(&self) -> &TimemHome {
+        &self.home
+    }
+
+    pub fn settings(&self) -> &GlobalSettings {
+        &self.settings
+    }
+
+    /// Iterates over the config entries that could not be loaded, with the reason why
+    pub fn iter_unavailable_dirs(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.unavailable_dirs
+            .iter()
+            .map(|(path, unavailable)| (path.as_path(), unavailable.reason.as_str()))
+    }
+
+    /// Returns the snapshot repo of every config entry, including the unavailable ones
+    pub fn iter_dotgit_dirs(&self) -> impl Iterator<Item = &Path> {
+        self.watched_dirs.values().map(WatchDir::dotgit_dir).chain(
+            self.unavailable_dirs.values().filter_map(|unavailable| {
+                unavailable
+                    .config
+                    .get("dotgit_dir")
+                    .and_then(serde_json::Value::as_str)
+                    .map(Path::new)
+            }),
+        )
+    }
+}
+
+/// Writes `content` to a temp file next to `path` and renames it over `path`, so readers never see
+/// a partially written file
+pub(crate) fn write_atomically(path: &Path, content: &str) -> Result<(), String> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut tmp_file = File::create(&tmp_path).map_err(|err| err.to_string())?;
+    tmp_file
+        .write_all(content.as_bytes())
+        .and_then(|_| tmp_file.sync_all())
+        .map_err(|err| err.to_string())?;
+    fs::rename(&tmp_path, path).map_err(|err| err.to_string())
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// A root of a watched directory, file or group in the directory trie, with the key of its entry
+/// in the watch list
+#[derive(Clone)]
+struct TrieEntry {
+    root: PathBuf,
+    key: PathBuf,
+}
+
+/// Maps every path snapshotted by a watched directory, file or group to the most specific root
+/// containing it
+fn build_trie(watched_dirs: &HashMap<PathBuf, WatchDir>) -> DirectoryTrie<TrieEntry> {
+    let mut dir_trie = DirectoryTrie::new();
+    for (path, watch_dir) in watched_dirs.iter() {
+        for root in watch_dir.roots() {
+            dir_trie.insert(
+                &root,
+                TrieEntry {
+                    root: root.clone(),
+                    key: path.clone(),
+                },
+            );
+        }
+    }
+    dir_trie
+}
+
+/// Whether `path` is strictly inside one of `roots`
+fn is_nested(path: &Path, roots: &[PathBuf]) -> bool {
+    roots
+        .iter()
+        .any(|root| path != root.as_path() && path.starts_with(root))
+}
+
+/// Watches single files through their parent directory, as editors often save a file by renaming a
+/// new one over it, which a watch on the file itself would not survive. Events about the other
+/// files of the parent are sent too, and filtered out by the directory trie
+struct FileWatcher {
+    watcher: RecommendedWatcher,
+    /// Number of watched files in each watched parent directory
+    parents: HashMap<PathBuf, usize>,
+}
+
+impl FileWatcher {
+    fn new(tx: mpsc::Sender<NotifyResult<Event>>) -> Result<Self, String> {
+        Ok(Self {
+            watcher: Watcher::new(tx, NotifyConfig::default()).map_err(|err| err.to_string())?,
+            parents: HashMap::new(),
+        })
+    }
+
+    fn watch(&mut self, file: &Path) -> NotifyResult<()> {
+        let parent = file.parent().unwrap_or(file);
+        if !self.parents.contains_key(parent) {
+            self.watcher.watch(parent, RecursiveMode::NonRecursive)?;
+        }
+        *self.parents.entry(parent.to_owned()).or_insert(0) += 1;
+        Ok(())
+    }
+
+    fn unwatch(&mut self, file: &Path) -> NotifyResult<()> {
+        let parent = file.parent().unwrap_or(file);
+        match self.parents.get_mut(parent) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                Ok(())
+            }
+            Some(_) => {
+                self.parents.remove(parent);
+                self.watcher.unwatch(parent)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Watches the base directories of the watch patterns for new matching directories. Kept apart
+/// from the directory watcher, as a base directory may also be watched recursively there
+struct PatternWatcher {
+    watcher: RecommendedWatcher,
+    /// Watched base directories, and whether they are watched recursively
+    bases: HashMap<PathBuf, bool>,
+}
+
+impl PatternWatcher {
+    fn new(tx: mpsc::Sender<NotifyResult<Event>>) -> Result<Self, String> {
+        Ok(Self {
+            watcher: Watcher::new(tx, NotifyConfig::default()).map_err(|err| err.to_string())?,
+            bases: HashMap::new(),
+        })
+    }
+
+    /// Watches the base directories of `patterns`, unwatching the ones no longer needed
+    fn update(&mut self, patterns: &[WatchPattern]) {
+        let mut bases: HashMap<PathBuf, bool> = HashMap::new();
+        for (base, recursive) in patterns.iter().map(WatchPattern::base_dir) {
+            *bases.entry(base).or_default() |= recursive;
+        }
+
+        for (base, recursive) in self.bases.iter() {
+            if bases.get(base) != Some(recursive) {
+                if let Err(e) = self.watcher.unwatch(base) {
+                    log::debug!("Failed to unregister notify handler on dir {:?}: {e}", base);
+                }
+            }
+        }
+        for (base, recursive) in bases.iter() {
+            if self.bases.get(base) == Some(recursive) {
+                continue;
+            }
+            let mode = if *recursive {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            if let Err(e) = self.watcher.watch(base, mode) {
+                log::error!("Failed to register notify handler on dir {:?}: {e}", base);
+            }
+        }
+        self.bases = bases;
+    }
+}
+
+/// A config entry that failed to load, kept verbatim so it is not lost on the next write
+struct UnavailableDir {
+    config: serde_json::Value,
+    reason: String,
+}
+
+impl UnavailableDir {
+    fn from_watch_dir(watch_dir: &WatchDir, reason: String) -> Self {
+        Self {
+            config: serde_json::to_value(watch_dir).unwrap_or(serde_json::Value::Null),
+            reason,
+        }
+    }
+}
+
+struct LoadedConfig {
+    settings: GlobalSettings,
+    watched_dirs: HashMap<PathBuf, WatchDir>,
+    unavailable_dirs: HashMap<PathBuf, UnavailableDir>,
+    patterns: Vec<WatchPattern>,
+    stored_version: u32,
+}
+
+impl Default for LoadedConfig {
+    fn default() -> Self {
+        Self {
+            settings: GlobalSettings::default(),
+            watched_dirs: HashMap::new(),
+            unavailable_dirs: HashMap::new(),
+            patterns: Vec::new(),
+            stored_version: CONFIG_VERSION,
+        }
+    }
+}
+
+/// Advisory lock on `config.lock`, serializing config.json read-modify-write cycles between
+/// timemctl and timemserv. The file also holds the config generation, bumped on every write, so
+/// readers can tell whether config.json changed since they last loaded it
+struct ConfigLock {
+    file: File,
+}
+
+impl ConfigLock {
+    fn open(path: &Path) -> Result<File, String> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|err| err.to_string())
+    }
+
+    fn exclusive(path: &Path) -> Result<Self, String> {
+        let file = Self::open(path)?;
+        file.lock()
+            .map_err(|err| format!("Failed to lock {:?}: {err}", path))?;
+        Ok(Self { file })
+    }
+
+    fn shared(path: &Path) -> Result<Self, String> {
+        let file = Self::open(path)?;
+        file.lock_shared()
+            .map_err(|err| format!("Failed to lock {:?}: {err}", path))?;
+        Ok(Self { file })
+    }
+
+    fn generation(&mut self) -> u64 {
+        let mut content = String::new();
+        let _ = self
+            .file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.read_to_string(&mut content));
+        content.trim().parse().unwrap_or(0)
+    }
+
+    fn set_generation(&mut self, generation: u64) -> Result<(), String> {
+        self.file
+            .set_len(0)
+            .and_then(|_| self.file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(self.file, "{generation}"))
+            .and_then(|_| self.file.sync_all())
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl Drop for ConfigLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::scratch::ScratchDir;
+
+    /// Watches `outer/` and `outer/inner/` in a scratch home, under `policy`
+    fn nested_config(scratch: &ScratchDir, policy: NestedRoots) -> (Config, PathBuf, PathBuf) {
+        let outer = scratch.dir("outer");
+        let inner = scratch.dir("outer/inner");
+        let home = scratch.home();
+        let mut config = Config::new(&home, false).unwrap();
+        config.set_settings(GlobalSettings {
+            nested_roots: Some(policy),
+            ..Default::default()
+        });
+        for dir in [&outer, &inner] {
+            let watch_dir = WatchDir::new(&home, dir.clone(), Duration::from_secs(60), 0).unwrap();
+            config.add_watched_dir(watch_dir);
+        }
+        (config, outer, inner)
+    }
+
+    #[test]
+    fn excludes_nested_dir_from_outer_snapshots() {
+        let scratch = ScratchDir::new("nested-exclude");
+        let (config, outer, inner) = nested_config(&scratch, NestedRoots::Exclude);
+        assert_eq!(
+            config.watched_dirs[&outer].nested_roots(),
+            std::slice::from_ref(&inner)
+        );
+        assert!(config.watched_dirs[&inner].nested_roots().is_empty());
+
+        assert_eq!(config.owners_of(&inner.join("file")), [inner]);
+        assert_eq!(config.owners_of(&outer.join("file")), [outer]);
+    }
+
+    #[test]
+    fn includes_nested_dir_in_outer_snapshots() {
+        let scratch = ScratchDir::new("nested-include");
+        let (config, outer, inner) = nested_config(&scratch, NestedRoots::Include);
+        assert!(config.watched_dirs[&outer].nested_roots().is_empty());
+
+        assert_eq!(
+            config.owners_of(&inner.join("file")),
+            [inner, outer.clone()]
+        );
+        assert_eq!(config.owners_of(&outer.join("file")), [outer]);
+    }
 }
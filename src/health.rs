@@ -0,0 +1,201 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use hashbrown::hash_map::HashMap;
+
+use crate::config::write_atomically;
+use crate::TimemHome;
+
+/// Delay before the first retry of a failed snapshot. Doubles with every consecutive failure
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// Consecutive failures after which a directory is quarantined
+const QUARANTINE_THRESHOLD: u32 = 10;
+/// How often a quarantined directory is still retried, so it recovers once fixed
+const QUARANTINE_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Snapshot error state of a single watched directory
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DirHealth {
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_failure: Option<SystemTime>,
+    pub retry_after: Option<SystemTime>,
+    pub quarantined: bool,
+}
+
+impl DirHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures == 0
+    }
+}
+
+/// Per-directory snapshot error state, persisted to `health.json` by timemserv so timemctl can
+/// report it
+pub struct HealthState {
+    state_path: PathBuf,
+    dirs: HashMap<PathBuf, DirHealth>,
+}
+
+impl HealthState {
+    pub fn load(home: &TimemHome) -> Result<Self, String> {
+        let state_path = home.health_path();
+
+        let dirs = match fs::read_to_string(&state_path) {
+            Ok(content) if !content.trim().is_empty() => {
+                serde_json::from_str(&content).map_err(|err| err.to_string())?
+            }
+            _ => HashMap::new(),
+        };
+
+        Ok(Self { state_path, dirs })
+    }
+
+    pub fn flush(&self) -> Result<(), String> {
+        let content = serde_json::to_string(&self.dirs).map_err(|err| err.to_string())?;
+        // `timemctl status` may read it at any time
+        write_atomically(&self.state_path, &content)
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&DirHealth> {
+        self.dirs.get(path.as_ref())
+    }
+
+    /// Returns whether a snapshot of `path` should be attempted now, i.e. it isn't waiting out a
+    /// retry delay
+    pub fn should_attempt<P: AsRef<Path>>(&self, path: P) -> bool {
+        match self
+            .dirs
+            .get(path.as_ref())
+            .and_then(|health| health.retry_after)
+        {
+            Some(retry_after) => SystemTime::now() >= retry_after,
+            None => true,
+        }
+    }
+
+    /// Clears the error state of `path`. Returns whether anything changed
+    pub fn record_success<P: AsRef<Path>>(&mut self, path: P) -> bool {
+        let path = path.as_ref();
+        match self.dirs.remove(path) {
+            Some(health) => {
+                if health.quarantined {
+                    log::info!("Directory {:?} recovered, lifting quarantine", path);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records a failed snapshot of `path` and schedules its next retry
+    pub fn record_failure<P: AsRef<Path>>(&mut self, path: P, error: String) -> &DirHealth {
+        let path = path.as_ref();
+        let health = self.dirs.entry(path.to_owned()).or_default();
+        let now = SystemTime::now();
+
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        health.last_error = Some(error);
+        health.last_failure = Some(now);
+
+        if !health.quarantined && health.consecutive_failures >= QUARANTINE_THRESHOLD {
+            log::warn!(
+                "Quarantining {:?} after {} consecutive snapshot failures",
+                path,
+                health.consecutive_failures
+            );
+            health.quarantined = true;
+        }
+
+        let delay = if health.quarantined {
+            QUARANTINE_RETRY_DELAY
+        } else {
+            retry_delay(health.consecutive_failures)
+        };
+        health.retry_after = now.checked_add(delay);
+
+        health
+    }
+
+    /// Drops the state of directories that are no longer watched
+    pub fn retain_watched<F: FnMut(&Path) -> bool>(&mut self, mut is_watched: F) {
+        self.dirs.retain(|path, _| is_watched(path));
+    }
+}
+
+fn retry_delay(consecutive_failures: u32) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(16);
+    BASE_RETRY_DELAY
+        .saturating_mul(1 << exponent)
+        .min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_state() -> HealthState {
+        HealthState {
+            state_path: PathBuf::new(),
+            dirs: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn doubles_retry_delay_up_to_an_hour() {
+        assert_eq!(retry_delay(1), Duration::from_secs(30));
+        assert_eq!(retry_delay(2), Duration::from_secs(60));
+        assert_eq!(retry_delay(5), Duration::from_secs(480));
+        assert_eq!(retry_delay(7), Duration::from_secs(1920));
+        assert_eq!(retry_delay(8), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn waits_out_retry_delay() {
+        let mut state = empty_state();
+        let path = Path::new("/watched");
+        assert!(state.should_attempt(path));
+
+        let health = state.record_failure(path, "failed".to_owned());
+        let delay = health
+            .retry_after
+            .unwrap()
+            .duration_since(health.last_failure.unwrap())
+            .unwrap();
+        assert_eq!(delay, BASE_RETRY_DELAY);
+        assert!(!state.should_attempt(path));
+
+        state.dirs.get_mut(path).unwrap().retry_after = Some(SystemTime::now());
+        assert!(state.should_attempt(path));
+
+        assert!(state.record_success(path));
+        assert!(state.get(path).is_none());
+        assert!(state.should_attempt(path));
+    }
+
+    #[test]
+    fn quarantines_after_repeated_failures() {
+        let mut state = empty_state();
+        let path = Path::new("/watched");
+        for _ in 1..QUARANTINE_THRESHOLD {
+            assert!(!state.record_failure(path, "failed".to_owned()).quarantined);
+        }
+
+        let health = state.record_failure(path, "failed".to_owned());
+        assert!(health.quarantined);
+        assert_eq!(health.consecutive_failures, QUARANTINE_THRESHOLD);
+        let delay = health
+            .retry_after
+            .unwrap()
+            .duration_since(health.last_failure.unwrap())
+            .unwrap();
+        assert_eq!(delay, QUARANTINE_RETRY_DELAY);
+
+        state.record_success(path);
+        assert!(state.get(path).is_none());
+    }
+}
@@ -0,0 +1,78 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use directories::BaseDirs;
+
+/// Environment variable selecting the TimeM home directory
+pub const TIMEM_HOME_ENV: &str = "TIMEM_HOME";
+
+/// Root directory holding TimeM's config, state files and snapshot repos. Passing a different
+/// home to `Config` and `WatchDir` gives a fully isolated profile (tests, a second daemon for an
+/// external drive, etc.)
+#[derive(Clone, Debug)]
+pub struct TimemHome {
+    root: PathBuf,
+}
+
+impl TimemHome {
+    /// Uses `root` as the home directory, creating it if needed
+    pub fn new(root: PathBuf) -> Result<Self, String> {
+        if !root.exists() {
+            fs::create_dir_all(&root)
+                .map_err(|err| format!("Failed to create config directory {:?}: {err}", root))?;
+        }
+        let root = root.canonicalize().map_err(|err| err.to_string())?;
+        Ok(Self { root })
+    }
+
+    /// Picks the home directory from `root_override` (the `--config` flag), then `$TIMEM_HOME`,
+    /// then `timem/` in the OS config directory
+    pub fn resolve(root_override: Option<PathBuf>) -> Result<Self, String> {
+        let root = match root_override {
+            Some(root) => root,
+            None => match env::var_os(TIMEM_HOME_ENV) {
+                Some(root) if !root.is_empty() => PathBuf::from(root),
+                _ => BaseDirs::new()
+                    .ok_or("Could not locate OS config directory")?
+                    .config_dir()
+                    .join("timem"),
+            },
+        };
+        Self::new(root)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.root.join("config.json")
+    }
+
+    pub fn toml_path(&self) -> PathBuf {
+        self.root.join("config.toml")
+    }
+
+    pub fn config_lock_path(&self) -> PathBuf {
+        self.root.join("config.lock")
+    }
+
+    pub fn health_path(&self) -> PathBuf {
+        self.root.join("health.json")
+    }
+
+    pub fn pid_path(&self) -> PathBuf {
+        self.root.join("timemserv.pid")
+    }
+
+    /// Directory holding the config.json backups made by `timemctl clear-conf`
+    pub fn backups_dir(&self) -> PathBuf {
+        self.root.join("backups")
+    }
+
+    /// Directory holding the snapshot repo of every watched directory
+    pub fn dotgit_dir_dir(&self) -> PathBuf {
+        self.root.join(".git_dirs")
+    }
+}
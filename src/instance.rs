@@ -0,0 +1,89 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::TimemHome;
+
+/// Exclusive advisory lock on `timemserv.pid` in the TimeM home directory, held by the running
+/// timemserv for its whole lifetime. The file doubles as the pidfile.
+///
+/// The lock is released by the OS when the holder exits, so a pidfile left behind by a crashed
+/// daemon is detected as stale and taken over.
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    pub fn acquire(home: &TimemHome) -> Result<Self, String> {
+        let path = home.pid_path();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|err| err.to_string())?;
+
+        match file.try_lock() {
+            Ok(_) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(match read_pid(&mut file) {
+                    Some(pid) => format!("timemserv is already running (pid {pid})"),
+                    None => "timemserv is already running".into(),
+                });
+            }
+            Err(TryLockError::Error(err)) => {
+                return Err(format!("Failed to lock {:?}: {err}", path));
+            }
+        }
+
+        if let Some(stale_pid) = read_pid(&mut file) {
+            log::warn!("Found stale pidfile of pid {stale_pid}, taking over");
+        }
+
+        file.set_len(0).map_err(|err| err.to_string())?;
+        file.seek(SeekFrom::Start(0))
+            .map_err(|err| err.to_string())?;
+        writeln!(file, "{}", std::process::id()).map_err(|err| err.to_string())?;
+        file.sync_all().map_err(|err| err.to_string())?;
+
+        Ok(Self { file, path })
+    }
+
+    /// Returns the PID of the running timemserv, or `None` if no daemon holds the lock
+    pub fn running_pid(home: &TimemHome) -> Result<Option<u32>, String> {
+        let path = home.pid_path();
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.to_string()),
+        };
+
+        match file.try_lock_shared() {
+            Ok(_) => {
+                // Nobody holds the lock, any PID in the file is stale
+                let _ = file.unlock();
+                Ok(None)
+            }
+            Err(TryLockError::WouldBlock) => Ok(read_pid(&mut file)),
+            Err(TryLockError::Error(err)) => Err(format!("Failed to lock {:?}: {err}", path)),
+        }
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.file.set_len(0) {
+            log::debug!("Failed to clear pidfile {:?}: {err}", self.path);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
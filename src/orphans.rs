@@ -0,0 +1,144 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use git2::Repository;
+
+use crate::watchdir::{
+    decode_dotgit_name, head_commit_time, GROUP_CONFIG_KEY, TARGET_DIR_CONFIG_KEY,
+};
+use crate::Config;
+
+/// A snapshot repo in the `.git_dirs` directory that no config entry points to, e.g. after
+/// `timemctl clearconf` or a lost config.json
+pub struct OrphanRepo {
+    dotgit_dir: PathBuf,
+    target_dir: Option<PathBuf>,
+    last_snapshot_time: Option<SystemTime>,
+}
+
+impl OrphanRepo {
+    /// Scans the `.git_dirs` directory of the config's home for repos not referenced by `config`.
+    /// Fails if config.json couldn't be loaded, as every repo would look orphaned then
+    pub fn find(config: &Config) -> Result<Vec<Self>, String> {
+        if let Some(err) = config.load_error() {
+            return Err(format!(
+                "Config failed to load, refusing to look for orphaned repos: {err}"
+            ));
+        }
+        let dotgit_dir_dir = config.home().dotgit_dir_dir();
+        let entries = match fs::read_dir(&dotgit_dir_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(format!("Failed to read {:?}: {err}", dotgit_dir_dir)),
+        };
+
+        let referenced: Vec<PathBuf> = config.iter_dotgit_dirs().map(canonical).collect();
+        let mut orphans: Vec<Self> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().map(|ty| ty.is_dir()).unwrap_or(false))
+            .map(|entry| entry.path())
+            .filter(|path| !referenced.contains(&canonical(path)))
+            .map(Self::inspect)
+            .collect();
+        orphans.sort_by(|a, b| a.dotgit_dir.cmp(&b.dotgit_dir));
+        Ok(orphans)
+    }
+
+    /// Works out the directory the repo snapshotted, preferring the one recorded in the repo
+    /// config over the escaped repo name. Group repos hold several directories, so they have none
+    fn inspect(dotgit_dir: PathBuf) -> Self {
+        let repo = Repository::open(&dotgit_dir).ok();
+        let recorded_target = repo.as_ref().and_then(|repo| {
+            let config = repo.config().ok()?;
+            if config.get_string(GROUP_CONFIG_KEY).is_ok() {
+                return None;
+            }
+            match config.get_string(TARGET_DIR_CONFIG_KEY) {
+                Ok(target_dir) => Some(PathBuf::from(target_dir)),
+                Err(_) => config
+                    .get_path("core.worktree")
+                    .ok()
+                    .map(|worktree| dotgit_dir.join(worktree)),
+            }
+        });
+        let target_dir = recorded_target
+            .or_else(|| decode_dotgit_name(dotgit_dir.file_name()?))
+            .filter(|path| path.is_absolute());
+
+        Self {
+            last_snapshot_time: repo.as_ref().and_then(head_commit_time),
+            target_dir,
+            dotgit_dir,
+        }
+    }
+
+    pub fn dotgit_dir(&self) -> &Path {
+        &self.dotgit_dir
+    }
+
+    /// The directory this repo holds snapshots of, if it could be determined
+    pub fn target_dir(&self) -> Option<&Path> {
+        self.target_dir.as_deref()
+    }
+
+    pub fn last_snapshot_time(&self) -> Option<SystemTime> {
+        self.last_snapshot_time
+    }
+
+    /// Total size in bytes of the files in the repo
+    pub fn disk_usage(&self) -> u64 {
+        dir_size(&self.dotgit_dir)
+    }
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(ty) if ty.is_dir() => dir_size(&entry.path()),
+            Ok(ty) if ty.is_file() => entry.metadata().map(|meta| meta.len()).unwrap_or(0),
+            _ => 0,
+        })
+        .sum()
+}
+
+fn canonical(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::scratch::ScratchDir;
+    use crate::WatchDir;
+
+    #[test]
+    fn decodes_escaped_names() {
+        assert_eq!(
+            decode_dotgit_name(OsStr::new("_d_home_d_my__dir_d____d_x")),
+            Some(PathBuf::from("/home/my_dir/_/x"))
+        );
+        assert_eq!(decode_dotgit_name(OsStr::new("_d_a_b")), None);
+    }
+
+    #[test]
+    fn refuses_to_find_orphans_without_config() {
+        let scratch = ScratchDir::new("orphans-broken-config");
+        let home = scratch.home();
+        WatchDir::new(&home, scratch.dir("project"), Duration::from_secs(60), 0).unwrap();
+        fs::write(home.config_path(), "{\"version\":").unwrap();
+
+        let config = Config::new(&home, false).unwrap();
+        assert!(config.load_error().is_some());
+        assert!(OrphanRepo::find(&config).is_err());
+    }
+}
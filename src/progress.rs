@@ -0,0 +1,15 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bumped as long-running work (staging, pruning, the service loop) makes progress, so a watchdog
+/// can tell slow work from a hang
+static PROGRESS: AtomicU64 = AtomicU64::new(0);
+
+/// Records that work is still making progress
+pub fn record_progress() {
+    PROGRESS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns a counter that keeps growing while work makes progress
+pub fn progress_count() -> u64 {
+    PROGRESS.load(Ordering::Relaxed)
+}
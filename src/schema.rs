@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{Pause, SnapshotIdentity, WatchPattern};
+
+/// Current config.json schema version
+pub const CONFIG_VERSION: u32 = 1;
+
+/// Migrations from every older schema version, indexed by the version they migrate from
+const MIGRATIONS: [fn(Value) -> Result<Value, String>; CONFIG_VERSION as usize] =
+    [migrate_v0_to_v1];
+
+/// Settings that apply to every watched directory
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GlobalSettings {
+    /// Snapshot identity for watched directories that don't configure their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<SnapshotIdentity>,
+    /// Frequency for directories declared in config.toml that don't specify one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<Duration>,
+    /// Max file size for directories declared in config.toml that don't specify one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+    /// gitignore-style patterns excluded from the snapshots of every watched directory
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignores: Vec<String>,
+    /// How long snapshots are kept for watched directories that don't configure their own
+    /// retention
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Duration>,
+    /// Pause of every watched directory, set by `timemctl pause` without a directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pause: Option<Pause>,
+    /// What the snapshots of a watched directory do with the watched directories nested in it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nested_roots: Option<NestedRoots>,
+}
+
+/// Policy for watched directories (or files) inside another watched directory. Either way a change
+/// under the nested one only marks the nested one, the most specific owner, as changed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NestedRoots {
+    /// The outer directory's snapshots leave the nested one out, as it has snapshots of its own
+    #[default]
+    Exclude,
+    /// The outer directory's snapshots include the nested one too, and a change under the nested
+    /// one marks both as changed
+    Include,
+}
+
+/// On-disk shape of config.json. Watched directories are kept as raw values so that a single
+/// broken entry doesn't fail the whole document
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct ConfigDocument {
+    pub version: u32,
+    #[serde(default)]
+    pub settings: GlobalSettings,
+    #[serde(default)]
+    pub dirs: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patterns: Vec<WatchPattern>,
+}
+
+impl ConfigDocument {
+    /// Parses config.json contents, migrating older schema versions forward. Also returns the
+    /// version the contents were stored as
+    pub fn parse(content: &str) -> Result<(Self, u32), String> {
+        if content.trim().is_empty() {
+            return Ok((
+                Self {
+                    version: CONFIG_VERSION,
+                    ..Default::default()
+                },
+                CONFIG_VERSION,
+            ));
+        }
+
+        let mut value: Value = serde_json::from_str(content).map_err(|err| err.to_string())?;
+        let stored_version = match value.get("version") {
+            Some(version) => version
+                .as_u64()
+                .and_then(|version| u32::try_from(version).ok())
+                .ok_or("Invalid config.json version")?,
+            // Version 0 had no version field
+            None => 0,
+        };
+        if stored_version > CONFIG_VERSION {
+            return Err(format!(
+                "config.json has schema version {stored_version}, but this TimeM only supports up to {CONFIG_VERSION}. Upgrade TimeM"
+            ));
+        }
+
+        for migration in &MIGRATIONS[stored_version as usize..] {
+            value = migration(value)?;
+        }
+
+        let document = serde_json::from_value(value).map_err(|err| err.to_string())?;
+        Ok((document, stored_version))
+    }
+}
+
+/// Version 0 was a bare `{target_dir: WatchDir}` map
+fn migrate_v0_to_v1(value: Value) -> Result<Value, String> {
+    let Value::Object(map) = value else {
+        return Err("Invalid config.json contents, expected a JSON object".into());
+    };
+    let dirs: Vec<Value> = map.into_iter().map(|(_, watch_dir)| watch_dir).collect();
+    Ok(json!({
+        "version": 1,
+        "settings": {},
+        "dirs": dirs,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_bare_map_to_current_version() {
+        let v0 = r#"{"/a":{"target_dir":"/a"},"/b":{"target_dir":"/b"}}"#;
+        let (document, stored_version) = ConfigDocument::parse(v0).unwrap();
+        assert_eq!(stored_version, 0);
+        assert_eq!(document.version, CONFIG_VERSION);
+        assert_eq!(document.dirs.len(), 2);
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let future = format!(r#"{{"version":{},"dirs":[]}}"#, CONFIG_VERSION + 1);
+        assert!(ConfigDocument::parse(&future).is_err());
+    }
+}
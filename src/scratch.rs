@@ -0,0 +1,45 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::TimemHome;
+
+/// Directory for a test's files under the system temp dir, removed with its contents when dropped
+pub(crate) struct ScratchDir {
+    root: PathBuf,
+}
+
+impl ScratchDir {
+    /// Creates an empty `timem-<name>-<pid>` directory. Tests running in parallel need distinct
+    /// names
+    pub(crate) fn new(name: &str) -> Self {
+        let root = env::temp_dir().join(format!("timem-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        Self { root }
+    }
+
+    /// Creates the directory `relative` inside this one, returning its path
+    pub(crate) fn dir(&self, relative: &str) -> PathBuf {
+        let dir = self.root.join(relative);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A TimeM home in `home/` inside this directory
+    pub(crate) fn home(&self) -> TimemHome {
+        TimemHome::new(self.root.join("home")).unwrap()
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Writes `content` to `path`, creating its parent directories
+pub(crate) fn write(path: &Path, content: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+}
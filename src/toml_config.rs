@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use directories::BaseDirs;
+
+use humantime::{format_duration, parse_duration};
+use parse_size::parse_size;
+
+use crate::{GlobalSettings, NestedRoots, SnapshotIdentity, WatchDir};
+
+/// Human-editable alternative to config.json, read from `config.toml` in the config directory.
+/// Durations and sizes are written like on the command line (e.g. `15m`, `5MiB`)
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TomlConfig {
+    #[serde(default)]
+    pub defaults: TomlSettings,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dirs: Vec<TomlDir>,
+}
+
+/// Settings shared by the `[defaults]` table and the per-directory overrides
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TomlSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignores: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<SnapshotIdentity>,
+    /// Only allowed in `[defaults]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nested_roots: Option<NestedRoots>,
+}
+
+/// A `[[dirs]]` entry: a watched directory with optional overrides of the defaults
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TomlDir {
+    /// The watched directory. A leading `~` is expanded to the home directory
+    pub path: String,
+    #[serde(flatten)]
+    pub overrides: TomlSettings,
+}
+
+impl TomlConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        toml::from_str(&content).map_err(|err| err.to_string())
+    }
+
+    pub fn to_toml_string(&self) -> Result<String, String> {
+        toml::to_string_pretty(self).map_err(|err| err.to_string())
+    }
+}
+
+impl TomlSettings {
+    pub fn to_global_settings(&self) -> Result<GlobalSettings, String> {
+        Ok(GlobalSettings {
+            identity: self.identity.clone(),
+            frequency: self
+                .frequency
+                .as_deref()
+                .map(parse_duration)
+                .transpose()
+                .map_err(|err| format!("Invalid frequency: {err}"))?,
+            max_file_size: self
+                .max_file_size
+                .as_deref()
+                .map(parse_size)
+                .transpose()
+                .map_err(|err| format!("Invalid max_file_size: {err}"))?,
+            ignores: self.ignores.clone(),
+            retention: self
+                .retention
+                .as_deref()
+                .map(parse_duration)
+                .transpose()
+                .map_err(|err| format!("Invalid retention: {err}"))?,
+            pause: None,
+            nested_roots: self.nested_roots,
+        })
+    }
+
+    pub fn from_global_settings(settings: &GlobalSettings) -> Self {
+        Self {
+            frequency: settings.frequency.map(duration_string),
+            max_file_size: settings.max_file_size.map(|size| size.to_string()),
+            ignores: settings.ignores.clone(),
+            retention: settings.retention.map(duration_string),
+            identity: settings.identity.clone(),
+            nested_roots: settings.nested_roots,
+        }
+    }
+}
+
+impl TomlDir {
+    pub fn from_watch_dir(watch_dir: &WatchDir) -> Self {
+        let max_file_size = watch_dir.max_file_size();
+        Self {
+            path: watch_dir.target_dir().display().to_string(),
+            overrides: TomlSettings {
+                frequency: Some(duration_string(watch_dir.frequency())),
+                max_file_size: (max_file_size != u64::MAX).then(|| max_file_size.to_string()),
+                ignores: watch_dir.ignores().to_vec(),
+                retention: watch_dir.retention().map(duration_string),
+                identity: watch_dir.identity().cloned(),
+                nested_roots: None,
+            },
+        }
+    }
+
+    /// Returns the directory path with `~` expanded
+    pub fn expanded_path(&self) -> Result<PathBuf, String> {
+        expand_home(&self.path)
+    }
+}
+
+/// Expands a leading `~` in `path` to the home directory
+pub(crate) fn expand_home(path: &str) -> Result<PathBuf, String> {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest.trim_start_matches('/'),
+        _ => return Ok(PathBuf::from(path)),
+    };
+    let base_dirs = BaseDirs::new().ok_or("Could not locate home directory")?;
+    Ok(base_dirs.home_dir().join(rest))
+}
+
+fn duration_string(duration: Duration) -> String {
+    format_duration(duration).to_string()
+}
@@ -0,0 +1,150 @@
+use std::env;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use glob::{MatchOptions, Pattern};
+
+use humantime::format_duration;
+
+use crate::toml_config::expand_home;
+
+/// `*` stays within a path component and doesn't match hidden directories
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: true,
+};
+
+/// A glob pattern whose matching directories are all watched with the same settings, including
+/// the ones created after the pattern was added
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WatchPattern {
+    /// Absolute glob pattern, e.g. `/home/me/projects/*`
+    pattern: String,
+    frequency: Duration,
+    /// Max file size in bytes, 0 meaning unlimited
+    #[serde(default)]
+    max_file_size: u64,
+}
+
+impl WatchPattern {
+    /// Parses `pattern`, expanding a leading `~` and resolving it against the current directory
+    pub fn new(pattern: &str, frequency: Duration, max_file_size: u64) -> Result<Self, String> {
+        let path = expand_home(pattern)?;
+        let path = if path.is_absolute() {
+            path
+        } else {
+            env::current_dir()
+                .map_err(|err| err.to_string())?
+                .join(path)
+        };
+        let pattern = path
+            .to_str()
+            .ok_or("Pattern is not valid UTF-8")?
+            .to_owned();
+        Pattern::new(&pattern).map_err(|err| format!("Invalid pattern: {err}"))?;
+        Ok(Self {
+            pattern,
+            frequency,
+            max_file_size,
+        })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn frequency(&self) -> Duration {
+        self.frequency
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    /// Whether `path` is a directory matching the pattern
+    pub fn matches(&self, path: &Path) -> bool {
+        Pattern::new(&self.pattern)
+            .is_ok_and(|pattern| pattern.matches_path_with(path, MATCH_OPTIONS))
+            && path.is_dir()
+    }
+
+    /// Returns the existing directories matching the pattern
+    pub fn find_matches(&self) -> Result<Vec<PathBuf>, String> {
+        let paths = glob::glob_with(&self.pattern, MATCH_OPTIONS).map_err(|err| err.to_string())?;
+        Ok(paths
+            .filter_map(|path| {
+                path.map_err(|err| log::debug!("Skipping pattern match: {err}"))
+                    .ok()
+            })
+            .filter(|path| path.is_dir())
+            .collect())
+    }
+
+    /// Returns the directory in which new matches appear, the part of the pattern before the
+    /// first wildcard, and whether it must be watched recursively as the wildcards span several
+    /// levels
+    pub fn base_dir(&self) -> (PathBuf, bool) {
+        let components: Vec<&str> = self.pattern.split('/').collect();
+        let first_wildcard = components
+            .iter()
+            .position(|component| component.contains(['*', '?', '[']))
+            .unwrap_or(components.len() - 1);
+        let base = match components[..first_wildcard].join("/") {
+            base if base.is_empty() => PathBuf::from("/"),
+            base => PathBuf::from(base),
+        };
+        let recursive = components.len() - first_wildcard > 1
+            || self.pattern[base.as_os_str().len()..].contains("**");
+        (base, recursive)
+    }
+}
+
+impl Display for WatchPattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "{} checked every {}",
+            self.pattern,
+            format_duration(self.frequency)
+        )?;
+        if self.max_file_size != 0 {
+            write!(f, ", files up to {} bytes", self.max_file_size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pattern: &str) -> WatchPattern {
+        WatchPattern::new(pattern, Duration::from_secs(60), 0).unwrap()
+    }
+
+    #[test]
+    fn splits_base_dir() {
+        assert_eq!(
+            pattern("/home/me/projects/*").base_dir(),
+            (PathBuf::from("/home/me/projects"), false)
+        );
+        assert_eq!(
+            pattern("/home/me/*/src").base_dir(),
+            (PathBuf::from("/home/me"), true)
+        );
+        assert_eq!(
+            pattern("/home/me/projects/**").base_dir(),
+            (PathBuf::from("/home/me/projects"), true)
+        );
+        assert_eq!(pattern("/*").base_dir(), (PathBuf::from("/"), false));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(WatchPattern::new("/home/me/[projects", Duration::from_secs(60), 0).is_err());
+    }
+}
@@ -1,14 +1,16 @@
 use git2::{
-    build::CheckoutBuilder, Commit, ErrorCode::UnbornBranch, Oid, Repository,
-    RepositoryInitOptions, StatusOptions,
+    build::CheckoutBuilder, Commit, ConfigLevel, ErrorCode::UnbornBranch, Index, IndexEntry,
+    IndexTime, ObjectType, Oid, Repository, RepositoryInitOptions, Signature, Tree, TreeWalkMode,
+    TreeWalkResult,
 };
 use std::cell::Cell;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::fs;
+use std::io;
 use std::path::MAIN_SEPARATOR;
 use std::path::{Path, PathBuf};
-use std::time::{self, Duration, Instant, SystemTime};
+use std::time::{self, Duration, SystemTime};
 
 use anyhow::Error;
 
@@ -16,158 +18,421 @@ use serde::{Deserialize, Deserializer, Serialize};
 
 use bstr::ByteSlice;
 
-use humantime::format_duration;
+use sha2::{Digest, Sha256};
 
-use crate::{exit_error, DOTGIT_DIR_DIR};
+use humantime::{format_duration, format_rfc3339_seconds};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::{record_progress, GlobalSettings, TimemHome};
+
+/// Author/committer identity used for snapshot commits
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotIdentity {
+    pub name: String,
+    pub email: String,
+}
+
+/// Suspension of snapshots, either indefinite or until a point in time
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pause {
+    /// When the pause expires by itself, `None` meaning it lasts until resumed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<SystemTime>,
+}
+
+impl Pause {
+    pub fn is_active(&self) -> bool {
+        self.until.is_none_or(|until| SystemTime::now() < until)
+    }
+}
+
+impl Display for Pause {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self.until {
+            Some(until) => write!(f, "paused until {}", format_rfc3339_seconds(until)),
+            None => write!(f, "paused"),
+        }
+    }
+}
+
+/// What `WatchDir::snapshot` did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotOutcome {
+    /// The next snapshot isn't due yet
+    NotDue,
+    /// The snapshot would have been identical to the last one
+    NoChanges,
+    Committed,
+}
+
+/// Directory watched as part of a group, stored under `prefix` in the group's snapshots
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GroupRoot {
+    pub prefix: String,
+    pub path: PathBuf,
+}
 
 #[derive(Serialize)]
 pub struct WatchDir {
     target_dir: PathBuf,
     dotgit_dir: PathBuf,
+    /// `target_dir` is a single file, snapshotted on its own
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    is_file: bool,
+    /// Name of the group, if this watches a group of directories (`roots`) sharing one repo and
+    /// schedule. `target_dir` is then the first root, which also keys the group in the config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    roots: Vec<GroupRoot>,
     #[serde(skip)]
     repo: Repository,
     frequency: Duration,
+    /// Wall-clock time of the last snapshot, derived from the HEAD commit so the schedule
+    /// survives daemon restarts. Without any snapshot yet, the first one is due right away
     #[serde(skip)]
-    last_snapshot_time: Cell<Instant>,
+    last_snapshot_time: Cell<SystemTime>,
     max_file_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity: Option<SnapshotIdentity>,
+    /// gitignore-style patterns excluded from snapshots, on top of the global ones
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    ignores: Vec<String>,
+    /// How long snapshots are kept. Falls back to the global retention, and to forever
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retention: Option<Duration>,
+    /// Snapshots are suspended while this is active. Changes keep being tracked meanwhile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pause: Option<Pause>,
+    /// Also snapshot the `.git` directories of the watched directory and of repos nested in it
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    include_git_metadata: bool,
+    /// Global settings, used where this directory doesn't configure its own
+    #[serde(skip)]
+    defaults: GlobalSettings,
+    /// Watched directories and files inside this one that its snapshots leave out, maintained by
+    /// `Config` according to the `nested_roots` policy
+    #[serde(skip)]
+    nested_roots: Vec<PathBuf>,
+    /// The global and per-directory ignore patterns, matched against paths as stored in snapshots
+    #[serde(skip)]
+    ignore_matcher: Gitignore,
 }
 
 #[derive(Deserialize)]
 struct WatchDirHelper {
     target_dir: PathBuf,
     dotgit_dir: PathBuf,
+    #[serde(default)]
+    is_file: bool,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default)]
+    roots: Vec<GroupRoot>,
     frequency: Duration,
-    #[serde(skip, default = "Instant::cell_default")]
-    last_snapshot_time: Cell<Instant>,
     max_file_size: u64,
+    #[serde(default)]
+    identity: Option<SnapshotIdentity>,
+    #[serde(default)]
+    ignores: Vec<String>,
+    #[serde(default)]
+    retention: Option<Duration>,
+    #[serde(default)]
+    pause: Option<Pause>,
+    #[serde(default)]
+    include_git_metadata: bool,
+}
+
+impl WatchDirHelper {
+    /// Describes the missing watched file, directory or group root, if any
+    fn missing_target(&self) -> Option<String> {
+        if self.is_file {
+            return (!self.target_dir.is_file()).then(|| "Target file does not exist".to_owned());
+        }
+        if self.group.is_some() {
+            return self
+                .roots
+                .iter()
+                .find(|root| !root.path.is_dir())
+                .map(|root| format!("Group root {:?} does not exist", root.path));
+        }
+        (!self.target_dir.is_dir()).then(|| "Target directory does not exist".to_owned())
+    }
 }
 
 impl WatchDir {
     pub fn new(
+        home: &TimemHome,
         target_dir: PathBuf,
         frequency: Duration,
         max_file_size: u64,
     ) -> Result<Self, Error> {
-        let dir_os_str = target_dir
-            .as_os_str()
-            .as_encoded_bytes()
-            .replace(b"_", b"__")
-            .replace(MAIN_SEPARATOR.to_string(), b"_d_");
-        if let Some(mut dotgit_dir) = DOTGIT_DIR_DIR.clone() {
-            dotgit_dir.push(unsafe { OsStr::from_encoded_bytes_unchecked(dir_os_str.as_slice()) });
-            let max_file_size = if max_file_size == 0 {
-                u64::MAX
-            } else {
-                max_file_size
-            };
-
-            // If we have not created our .git directory for this watched dir yet
-            if !dotgit_dir.exists() {
-                std::fs::create_dir_all(&dotgit_dir)?;
-                let mut opts = RepositoryInitOptions::new();
-                opts.external_template(true).bare(false);
-
-                let repo = Repository::init_opts(&target_dir, &opts)?;
+        let mut dotgit_dir = home.dotgit_dir_dir();
+        dotgit_dir.push(encode_dotgit_name(&target_dir));
+        Self::init_or_open(dotgit_dir, target_dir, frequency, max_file_size)
+    }
 
-                let repo_git_dir = repo.path().to_path_buf();
-                let target_git_dir = Path::new(&dotgit_dir);
+    /// Watches `roots` as the group `name`: one snapshot repo and schedule for all of them, each
+    /// snapshot storing every root under its prefix
+    pub fn new_group(
+        home: &TimemHome,
+        name: &str,
+        roots: Vec<GroupRoot>,
+        frequency: Duration,
+        max_file_size: u64,
+    ) -> Result<Self, Error> {
+        validate_group(name, &roots)?;
+        let dotgit_dir = home.dotgit_dir_dir().join(format!("_g_{name}"));
+        let mut watch_dir =
+            Self::init_or_open(dotgit_dir, roots[0].path.clone(), frequency, max_file_size)?;
+        watch_dir
+            .repo
+            .config()?
+            .open_level(ConfigLevel::Local)?
+            .set_str(GROUP_CONFIG_KEY, name)?;
+        watch_dir.group = Some(name.to_owned());
+        watch_dir.roots = roots;
+        Ok(watch_dir)
+    }
 
-                std::fs::rename(&repo_git_dir, &target_git_dir)?;
+    fn init_or_open(
+        dotgit_dir: PathBuf,
+        target_dir: PathBuf,
+        frequency: Duration,
+        max_file_size: u64,
+    ) -> Result<Self, Error> {
+        // If we have not created our .git directory for this watched dir yet
+        let is_new = !dotgit_dir.exists();
+        let result = if is_new {
+            init_repo(&dotgit_dir, worktree_dir(&target_dir, target_dir.is_file()))
+                .and_then(|_| Self::open(dotgit_dir.clone(), target_dir, frequency, max_file_size))
+        } else {
+            Self::open(dotgit_dir.clone(), target_dir, frequency, max_file_size)
+        };
 
-                repo.set_workdir(Path::new(&target_dir), true)?;
+        if is_new && result.is_err() && dotgit_dir.exists() {
+            // A half-initialized repo would be picked up as is next time
+            if let Err(err) = fs::remove_dir_all(&dotgit_dir) {
+                log::warn!("Failed to clean up {:?}: {err}", dotgit_dir);
             }
+        }
+        result
+    }
 
-            let repo = Repository::open(&dotgit_dir)?;
-            repo.set_workdir(&target_dir, false)?;
-
-            Ok(Self {
-                target_dir,
-                frequency,
-                max_file_size,
-                dotgit_dir,
-                repo,
-                last_snapshot_time: Cell::new(Instant::now()),
-            })
+    /// Watches `target_dir`, a directory or a single file, using the existing snapshot repo at
+    /// `dotgit_dir`
+    pub fn open(
+        dotgit_dir: PathBuf,
+        target_dir: PathBuf,
+        frequency: Duration,
+        max_file_size: u64,
+    ) -> Result<Self, Error> {
+        let max_file_size = if max_file_size == 0 {
+            u64::MAX
         } else {
-            exit_error!("Could not locate OS config directory");
-        }
+            max_file_size
+        };
+
+        let is_file = target_dir.is_file();
+        let repo = Repository::open(&dotgit_dir)?;
+        repo.set_workdir(worktree_dir(&target_dir, is_file), false)?;
+        record_target_dir(&repo, &target_dir);
+
+        Ok(Self {
+            target_dir,
+            is_file,
+            group: None,
+            roots: Vec::new(),
+            frequency,
+            max_file_size,
+            dotgit_dir,
+            last_snapshot_time: Cell::new(head_commit_time(&repo).unwrap_or(time::UNIX_EPOCH)),
+            repo,
+            identity: None,
+            ignores: Vec::new(),
+            retention: None,
+            pause: None,
+            include_git_metadata: false,
+            defaults: GlobalSettings::default(),
+            nested_roots: Vec::new(),
+            ignore_matcher: Gitignore::empty(),
+        })
     }
 
     pub fn target_dir(&self) -> &Path {
         self.target_dir.as_path()
     }
 
-    pub fn snapshot(&self, force: bool) -> Result<bool, Error> {
-        let last_snapshot_time = self.last_snapshot_time.get();
-        if !force && Instant::now() < last_snapshot_time.checked_add(self.frequency).unwrap() {
-            return Ok(false);
+    pub fn dotgit_dir(&self) -> &Path {
+        self.dotgit_dir.as_path()
+    }
+
+    /// Whether a single file is watched rather than a directory
+    pub fn is_file(&self) -> bool {
+        self.is_file
+    }
+
+    /// Name of the group, if this watches a group of directories
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn group_roots(&self) -> &[GroupRoot] {
+        &self.roots
+    }
+
+    /// Returns the paths that are snapshotted: the roots of a group, or the watched directory or
+    /// file
+    pub fn roots(&self) -> Vec<PathBuf> {
+        if self.group.is_some() {
+            self.roots.iter().map(|root| root.path.clone()).collect()
+        } else {
+            vec![self.target_dir.clone()]
         }
+    }
 
-        let mut index = self.repo.index()?;
+    pub fn identity(&self) -> Option<&SnapshotIdentity> {
+        self.identity.as_ref()
+    }
 
-        for entry in fs::read_dir(&self.target_dir)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| {
-                entry
-                    .metadata()
-                    .map(|meta| meta.len() <= self.max_file_size)
-                    .unwrap_or(false)
-            })
-        {
-            let path = entry.path();
-            let relative_path = path.strip_prefix(&self.target_dir)?;
-            index.add_path(relative_path)?;
+    pub fn set_identity(&mut self, identity: Option<SnapshotIdentity>) {
+        self.identity = identity;
+    }
+
+    pub fn frequency(&self) -> Duration {
+        self.frequency
+    }
+
+    pub fn set_frequency(&mut self, frequency: Duration) {
+        self.frequency = frequency;
+    }
+
+    /// Max file size in bytes, `u64::MAX` meaning unlimited
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    /// Sets the max file size in bytes, 0 meaning unlimited
+    pub fn set_max_file_size(&mut self, max_file_size: u64) {
+        self.max_file_size = if max_file_size == 0 {
+            u64::MAX
+        } else {
+            max_file_size
+        };
+    }
+
+    pub fn ignores(&self) -> &[String] {
+        &self.ignores
+    }
+
+    pub fn set_ignores(&mut self, ignores: Vec<String>) -> Result<(), Error> {
+        self.ignores = ignores;
+        self.apply_ignore_rules()
+    }
+
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention
+    }
+
+    pub fn set_retention(&mut self, retention: Option<Duration>) {
+        self.retention = retention;
+    }
+
+    pub fn pause(&self) -> Option<Pause> {
+        self.pause
+    }
+
+    pub fn set_pause(&mut self, pause: Option<Pause>) {
+        self.pause = pause;
+    }
+
+    /// Returns the pause currently in effect for this directory, its own or the global one
+    pub fn active_pause(&self) -> Option<Pause> {
+        self.pause
+            .into_iter()
+            .chain(self.defaults.pause)
+            .find(Pause::is_active)
+    }
+
+    pub fn include_git_metadata(&self) -> bool {
+        self.include_git_metadata
+    }
+
+    pub fn set_include_git_metadata(&mut self, include_git_metadata: bool) {
+        self.include_git_metadata = include_git_metadata;
+    }
+
+    /// Watched entries inside this one that its snapshots leave out
+    pub fn nested_roots(&self) -> &[PathBuf] {
+        &self.nested_roots
+    }
+
+    pub(crate) fn set_nested_roots(&mut self, nested_roots: Vec<PathBuf>) {
+        self.nested_roots = nested_roots;
+    }
+
+    pub(crate) fn set_defaults(&mut self, defaults: &GlobalSettings) -> Result<(), Error> {
+        self.defaults = defaults.clone();
+        self.apply_ignore_rules()
+    }
+
+    /// Rebuilds the matcher of the global and per-directory ignore patterns. Only these apply:
+    /// the watched directory's own `.gitignore` files and the user's git excludes are not used,
+    /// as what git doesn't track (e.g. `.env` files) still deserves a backup
+    fn apply_ignore_rules(&mut self) -> Result<(), Error> {
+        let mut builder = GitignoreBuilder::new("");
+        for rule in self.defaults.ignores.iter().chain(self.ignores.iter()) {
+            builder.add_line(None, rule)?;
         }
+        self.ignore_matcher = builder.build()?;
+        Ok(())
+    }
 
-        // Handle deleted files
-        for status_entry in self
-            .repo
-            .statuses(Some(
-                StatusOptions::new()
-                    .include_untracked(false)
-                    .include_ignored(true),
-            ))?
-            .iter()
-        {
-            let path = Path::new(
-                status_entry
-                    .path()
-                    .ok_or(Error::msg("Could not get path from git2 StatusEntry"))?,
-            );
-            if !path.exists() {
-                index.remove_path(path)?;
+    /// Returns the signature used for snapshot commits. A configured identity takes precedence
+    /// (the directory's own, then the global one), then the git config (`user.name`/`user.email`),
+    /// and finally `timem@<hostname>`
+    pub fn signature(&self) -> Result<Signature<'static>, Error> {
+        if let Some(identity) = self.identity.as_ref().or(self.defaults.identity.as_ref()) {
+            return Ok(Signature::now(&identity.name, &identity.email)?);
+        }
+
+        match self.repo.signature() {
+            Ok(signature) => Ok(signature.to_owned()),
+            Err(err) => {
+                log::debug!(
+                    "No git signature available for {:?} ({err}), using fallback identity",
+                    &self.target_dir
+                );
+                Ok(fallback_signature()?)
             }
         }
+    }
 
-        index.write()?;
+    /// Commits a snapshot if one is due (or `force` is set) and anything changed since the last
+    pub fn snapshot(&self, force: bool) -> Result<SnapshotOutcome, Error> {
+        // A frequency too large to add to the last snapshot time is never due
+        let due = self
+            .last_snapshot_time
+            .get()
+            .checked_add(self.frequency)
+            .is_some_and(|due_time| SystemTime::now() >= due_time);
+        if !force && !due {
+            return Ok(SnapshotOutcome::NotDue);
+        }
 
+        let (mut index, repos) = self.stage()?;
+        index.write()?;
         let oid = index.write_tree()?;
         let tree = self.repo.find_tree(oid)?;
 
-        let head_commit = self
-            .repo
-            .head()
-            .ok()
-            .and_then(|head| head.peel_to_commit().ok());
-        let has_changes = if let Some(head_commit) = head_commit {
-            let head_tree = head_commit.tree()?;
-            !self
-                .repo
-                .diff_tree_to_tree(Some(&head_tree), Some(&tree), None)?
-                .deltas()
-                .count()
-                > 0
-        } else {
-            true
-        };
-
-        if !has_changes {
-            log::info!("No changes to commit");
-            return Ok(false);
+        if !self.differs_from_head(oid) {
+            log::info!("No changes to commit in {}", self.describe());
+            return Ok(SnapshotOutcome::NoChanges);
         }
 
         let time = SystemTime::now().duration_since(time::UNIX_EPOCH)?;
-        let signature = self.repo.signature()?;
+        let signature = self.signature()?;
         let parents = if let Ok(head) = self.repo.head() {
             vec![head.peel_to_commit()?]
         } else {
@@ -177,20 +442,361 @@ impl WatchDir {
             Some("HEAD"),
             &signature,
             &signature,
-            &format!("TimeM snapshot at {:?}", time),
+            &snapshot_message(time, &repos),
             &tree,
             &parents.iter().collect::<Vec<_>>(),
         )?;
 
-        self.last_snapshot_time.set(Instant::now());
+        self.last_snapshot_time.set(SystemTime::now());
+
+        log::info!("Snapshotted {} to {:?}", self.describe(), &self.dotgit_dir);
+
+        Ok(SnapshotOutcome::Committed)
+    }
+
+    /// Drops snapshots older than the retention period, always keeping the latest one. The oldest
+    /// kept snapshot is grafted to have no parent (like in a shallow clone), so the kept snapshots
+    /// keep their hashes, then the dropped ones are deleted from the repo. Does nothing while a
+    /// restored snapshot is checked out. Returns the number of dropped snapshots
+    pub fn prune_snapshots(&mut self) -> Result<usize, Error> {
+        let Some(retention) = self.retention.or(self.defaults.retention) else {
+            return Ok(0);
+        };
+        let Some(cutoff) = SystemTime::now().checked_sub(retention) else {
+            return Ok(0);
+        };
+        let cutoff = cutoff.duration_since(time::UNIX_EPOCH)?.as_secs() as i64;
+
+        let Some((pruned, graft)) = self.find_graft(cutoff)? else {
+            return Ok(0);
+        };
+        write_shallow_graft(&self.repo, graft)?;
+        self.collect_garbage()?;
+        // Reopened, so that the graft is seen and the deleted packs are let go of
+        let repo = Repository::open(&self.dotgit_dir)?;
+        repo.set_workdir(worktree_dir(&self.target_dir, self.is_file), false)?;
+        self.repo = repo;
 
         log::info!(
-            "Snapshotted directory {:?} to {:?}",
-            &self.target_dir,
-            &self.dotgit_dir
+            "Pruned {pruned} snapshots of {} older than {}",
+            self.describe(),
+            format_duration(retention)
         );
+        Ok(pruned)
+    }
+
+    /// Returns the number of snapshots committed before `cutoff` (in seconds since the epoch),
+    /// and the oldest one to keep, unless there are none to drop
+    fn find_graft(&self, cutoff: i64) -> Result<Option<(usize, Oid)>, Error> {
+        let head = match self.repo.head() {
+            Ok(head) => head,
+            // Nothing to prune before the first snapshot
+            Err(err) if err.code() == UnbornBranch => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if !head.is_branch() {
+            return Ok(None);
+        }
+
+        // Newest first. Stops at the graft of a previous prune
+        let mut history = vec![head.peel_to_commit()?];
+        while let Ok(parent) = history[history.len() - 1].parent(0) {
+            record_progress();
+            history.push(parent);
+        }
+        let keep = history
+            .iter()
+            .take_while(|commit| commit.committer().when().seconds() >= cutoff)
+            .count()
+            .max(1);
+        if keep == history.len() {
+            return Ok(None);
+        }
+        Ok(Some((history.len() - keep, history[keep - 1].id())))
+    }
+
+    /// Deletes the snapshots no longer reachable: drops the reflogs, which would keep them
+    /// reachable, then repacks everything reachable from the refs, HEAD and the index into a
+    /// single pack, and deletes the previous packs and loose objects
+    fn collect_garbage(&self) -> Result<(), Error> {
+        // Freshly opened, as an open repo doesn't notice a new shallow graft
+        let repo = Repository::open(self.repo.path())?;
+        for reference in repo.references()?.names() {
+            repo.reflog_delete(reference?)?;
+        }
+        repo.reflog_delete("HEAD")?;
+
+        let mut walk = repo.revwalk()?;
+        walk.push_glob("*")?;
+        if repo.head().is_ok() {
+            walk.push_head()?;
+        }
+        let mut builder = repo.packbuilder()?;
+        builder.set_progress_callback(|_, _, _| {
+            record_progress();
+            true
+        })?;
+        builder.insert_walk(&mut walk)?;
+        let index_tree = repo.index()?.write_tree()?;
+        builder.insert_tree(index_tree)?;
+
+        let objects_dir = repo.path().join("objects");
+        let pack_dir = objects_dir.join("pack");
+        let previous_packs: Vec<PathBuf> = match fs::read_dir(&pack_dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<_, _>>()?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut pack = git2::Buf::new();
+        builder.write_buf(&mut pack)?;
+        // A pack is named after its trailing checksum
+        let checksum = pack
+            .len()
+            .checked_sub(20)
+            .map(|start| Oid::from_bytes(&pack[start..]))
+            .transpose()?
+            .ok_or(Error::msg("Invalid pack written"))?;
+        let odb = repo.odb()?;
+        let mut writer = odb.packwriter()?;
+        io::Write::write_all(&mut writer, &pack)?;
+        writer.commit()?;
+        let new_pack = format!("pack-{checksum}");
+        if !pack_dir.join(format!("{new_pack}.idx")).is_file() {
+            return Err(Error::msg(format!(
+                "Repacked objects not found as {new_pack}, keeping the previous objects"
+            )));
+        }
+
+        for path in previous_packs {
+            let is_new = path.file_stem() == Some(OsStr::new(&new_pack));
+            let is_pack = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| name.starts_with("pack-"));
+            if is_pack && !is_new {
+                fs::remove_file(&path)?;
+            }
+        }
+        for entry in fs::read_dir(&objects_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let is_loose_dir = name.len() == 2
+                && name
+                    .to_str()
+                    .is_some_and(|name| name.bytes().all(|byte| byte.is_ascii_hexdigit()));
+            if is_loose_dir {
+                fs::remove_dir_all(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether a snapshot taken now would differ from the last one. This is as expensive
+    /// as staging a snapshot: the whole tree is walked, and files whose size or mtime changed
+    /// since the last snapshot are hashed and written to the repo as blobs
+    pub fn has_pending_changes(&self) -> Result<bool, Error> {
+        let (mut index, _) = self.stage()?;
+        let oid = index.write_tree()?;
+        Ok(self.differs_from_head(oid))
+    }
 
-        Ok(true)
+    fn differs_from_head(&self, tree_oid: Oid) -> bool {
+        self.get_head_commit()
+            .map(|commit| commit.tree_id() != tree_oid)
+            .unwrap_or(true)
+    }
+
+    /// Replaces the index contents with the current contents of the watched directory, returning
+    /// the index and the git repos found in the directory. The index is not written to disk.
+    /// Files whose size and mtime match their index entry aren't hashed again. A watched file is
+    /// stored under its name, and a missing one is snapshotted as an empty tree
+    fn stage(&self) -> Result<(Index, Vec<NestedRepo>), Error> {
+        let mut index = self.repo.index()?;
+        let mut staging = Staging {
+            index: &index,
+            entries: Vec::new(),
+            repos: Vec::new(),
+        };
+        if self.is_file {
+            self.stage_file(&mut staging)?;
+        } else if self.group.is_some() {
+            for root in self.roots.iter() {
+                self.stage_dir(&mut staging, &root.path, Path::new(&root.prefix), false)?;
+            }
+        } else {
+            self.stage_dir(&mut staging, &self.target_dir, Path::new(""), false)?;
+        }
+        let Staging { entries, repos, .. } = staging;
+
+        index.clear()?;
+        for entry in entries.iter() {
+            index.add(entry)?;
+        }
+        Ok((index, repos))
+    }
+
+    /// Stages the files under `dir`, stored under `stored_dir` in the snapshot. Inside `.git`
+    /// metadata (`in_metadata`), ignore rules and the size limit don't apply. Entries deleted
+    /// while walking are skipped, as are unreadable ones after logging them
+    fn stage_dir(
+        &self,
+        staging: &mut Staging,
+        dir: &Path,
+        stored_dir: &Path,
+        in_metadata: bool,
+    ) -> Result<(), Error> {
+        for entry in fs::read_dir(dir)? {
+            record_progress();
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let Err(err) = self.stage_entry(staging, dir, &entry, stored_dir, in_metadata) else {
+                continue;
+            };
+            match err.downcast_ref::<io::Error>().map(io::Error::kind) {
+                Some(io::ErrorKind::NotFound) => {}
+                Some(io::ErrorKind::PermissionDenied) => {
+                    log::warn!("Skipping {:?}: {err}", entry.path());
+                }
+                _ => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Stages one entry of `dir`, walking into it if it is a directory
+    fn stage_entry(
+        &self,
+        staging: &mut Staging,
+        dir: &Path,
+        entry: &fs::DirEntry,
+        stored_dir: &Path,
+        in_metadata: bool,
+    ) -> Result<(), Error> {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if path == self.dotgit_dir || self.nested_roots.contains(&path) {
+            return Ok(());
+        }
+
+        let stored_path = if !in_metadata && entry.file_name() == ".git" {
+            // The watched directory or a nested directory is a git repo (or submodule).
+            // Its worktree is snapshotted as plain files, its metadata only on request
+            staging.repos.extend(NestedRepo::open(dir, stored_dir));
+            if !self.include_git_metadata {
+                return Ok(());
+            }
+            stored_dir.join(GIT_METADATA_NAME)
+        } else {
+            stored_dir.join(entry.file_name())
+        };
+        if !in_metadata
+            && self
+                .ignore_matcher
+                .matched(&stored_path, file_type.is_dir())
+                .is_ignore()
+        {
+            return Ok(());
+        }
+
+        let in_metadata = in_metadata || stored_path.ends_with(GIT_METADATA_NAME);
+        if file_type.is_dir() {
+            self.stage_dir(staging, &path, &stored_path, in_metadata)?;
+        } else if file_type.is_file() || file_type.is_symlink() {
+            let metadata = fs::symlink_metadata(&path)?;
+            if !in_metadata && metadata.len() > self.max_file_size {
+                return Ok(());
+            }
+            let entry = self.index_entry(staging.index, &path, &stored_path, &metadata)?;
+            staging.entries.push(entry);
+        }
+        Ok(())
+    }
+
+    fn stage_file(&self, staging: &mut Staging) -> Result<(), Error> {
+        let metadata = match fs::symlink_metadata(&self.target_dir) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if metadata.len() > self.max_file_size {
+            return Ok(());
+        }
+        let stored_path = self.stored_file_name()?;
+        let entry = self.index_entry(staging.index, &self.target_dir, stored_path, &metadata)?;
+        staging.entries.push(entry);
+        Ok(())
+    }
+
+    /// Name a watched file is stored under in its snapshots
+    fn stored_file_name(&self) -> Result<&Path, Error> {
+        self.target_dir
+            .file_name()
+            .map(Path::new)
+            .ok_or(Error::msg("Watched file has no name"))
+    }
+
+    fn index_entry(
+        &self,
+        index: &Index,
+        path: &Path,
+        stored_path: &Path,
+        metadata: &fs::Metadata,
+    ) -> Result<IndexEntry, Error> {
+        let mode = if metadata.file_type().is_symlink() {
+            0o120000
+        } else if is_executable(metadata) {
+            0o100755
+        } else {
+            0o100644
+        };
+        let mtime = metadata
+            .modified()?
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mtime = IndexTime::new(mtime.as_secs() as i32, mtime.subsec_nanos());
+        // Truncated like git does, the size only serves as a change hint
+        let file_size = metadata.len() as u32;
+
+        let cached_id = index
+            .get_path(stored_path, 0)
+            .filter(|entry| {
+                entry.mode == mode && entry.mtime == mtime && entry.file_size == file_size
+            })
+            .map(|entry| entry.id);
+        let id = match cached_id {
+            Some(id) => id,
+            None if mode == 0o120000 => self
+                .repo
+                .blob(fs::read_link(path)?.as_os_str().as_encoded_bytes())?,
+            // libgit2 reports a vanished or unreadable file as a generic error, opening it again
+            // tells which it was
+            None => self
+                .repo
+                .blob_path(path)
+                .map_err(|err| match fs::File::open(path) {
+                    Err(io_err) => Error::from(io_err),
+                    Ok(_) => Error::from(err),
+                })?,
+        };
+
+        Ok(IndexEntry {
+            ctime: mtime,
+            mtime,
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            file_size,
+            id,
+            flags: 0,
+            flags_extended: 0,
+            path: stored_path.as_os_str().as_encoded_bytes().to_vec(),
+        })
     }
 
     pub fn restore_snapshot(
@@ -202,6 +808,12 @@ impl WatchDir {
             .as_ref()
             .map(|p| p.as_ref())
             .unwrap_or(&self.target_dir);
+        if self.is_file {
+            return self.restore_file(commit, restore_to);
+        }
+        if self.group.is_some() && restore_to == self.target_dir {
+            return self.restore_group(commit);
+        }
         let mut checkout_builder = CheckoutBuilder::new();
         // self.repo.set_workdir(&self.dotgit_dir, false)?; // EXPERIMENTAL CODE
         checkout_builder.target_dir(restore_to);
@@ -210,7 +822,9 @@ impl WatchDir {
         let repo = Repository::open(&self.dotgit_dir)?;
         let commit = repo.find_commit(commit.id())?;
 
-        repo.checkout_tree(commit.tree()?.as_object(), Some(&mut checkout_builder))?;
+        let tree = commit.tree()?;
+        repo.checkout_tree(tree.as_object(), Some(&mut checkout_builder))?;
+        restore_git_metadata(&tree, restore_to)?;
 
         if restore_to_opt.is_none() || restore_to == self.target_dir {
             self.repo.set_head_detached(commit.id())?;
@@ -234,8 +848,131 @@ impl WatchDir {
         Ok(())
     }
 
+    /// Writes the watched file as of `commit` to `restore_to`, or into it if it is a directory.
+    /// Unlike directory restores, nothing else in the parent directory is touched
+    fn restore_file(&self, commit: Commit, restore_to: &Path) -> Result<(), Error> {
+        let stored_path = self.stored_file_name()?;
+        let entry = commit.tree()?.get_path(stored_path).map_err(|_| {
+            Error::msg(format!(
+                "{:?} did not exist at snapshot {}",
+                self.target_dir,
+                commit.id()
+            ))
+        })?;
+        let restore_to = if restore_to.is_dir() {
+            restore_to.join(stored_path)
+        } else {
+            restore_to.to_owned()
+        };
+        self.write_blob(entry.id(), entry.filemode(), &restore_to)?;
+
+        if restore_to == self.target_dir {
+            self.repo.set_head_detached(commit.id())?;
+        }
+        log::info!(
+            "Restored snapshot {:?} of {:?} to {:?}",
+            commit.id(),
+            self.target_dir,
+            restore_to
+        );
+        Ok(())
+    }
+
+    /// Writes every root of the group back as of `commit`, removing the files the current
+    /// snapshot has but `commit` doesn't. Everything is looked up for all roots before the first
+    /// file is written, so a missing root or object can't leave the roots at different points in
+    /// time
+    fn restore_group(&self, commit: Commit) -> Result<(), Error> {
+        let tree = commit.tree()?;
+        let head_tree = self
+            .get_head_commit()
+            .and_then(|head| Ok(head.tree()?))
+            .ok();
+        let odb = self.repo.odb()?;
+
+        let mut writes = Vec::new();
+        let mut removals = Vec::new();
+        let mut subtrees = Vec::new();
+        for root in self.roots.iter() {
+            if !root.path.is_dir() {
+                return Err(Error::msg(format!(
+                    "Group root {:?} does not exist",
+                    root.path
+                )));
+            }
+            let subtree = tree_at(&self.repo, &tree, &root.prefix).ok_or_else(|| {
+                Error::msg(format!(
+                    "Snapshot {} has no files of {:?}, it was empty or not in the group yet",
+                    commit.id(),
+                    root.path
+                ))
+            })?;
+            for (path, id, filemode) in tree_files(&subtree)? {
+                if !odb.exists(id) {
+                    return Err(Error::msg(format!("Object {id} of {:?} is missing", path)));
+                }
+                writes.push((root.path.join(path), id, filemode));
+            }
+            if let Some(previous) = head_tree
+                .as_ref()
+                .and_then(|head_tree| tree_at(&self.repo, head_tree, &root.prefix))
+            {
+                for (path, _, _) in tree_files(&previous)? {
+                    if subtree.get_path(&path).is_err() {
+                        removals.push(root.path.join(path));
+                    }
+                }
+            }
+            subtrees.push((subtree, &root.path));
+        }
+
+        for path in removals {
+            if path.is_file() || path.is_symlink() {
+                fs::remove_file(&path)?;
+            }
+        }
+        for (path, id, filemode) in writes {
+            self.write_blob(id, filemode, &path)?;
+        }
+        for (subtree, root) in subtrees {
+            restore_git_metadata(&subtree, root)?;
+        }
+
+        self.repo.set_head_detached(commit.id())?;
+        log::info!("Restored snapshot {:?} of {}", commit.id(), self.describe());
+        Ok(())
+    }
+
+    /// Writes the blob `id` to `path` as a file (or symlink) with `filemode`, replacing what is
+    /// there
+    fn write_blob(&self, id: Oid, filemode: i32, path: &Path) -> Result<(), Error> {
+        let blob = self.repo.find_blob(id)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if path.is_symlink() {
+            fs::remove_file(path)?;
+        }
+        if filemode == 0o120000 {
+            restore_symlink(blob.content(), path)
+        } else {
+            fs::write(path, blob.content())?;
+            set_executable(path, filemode == 0o100755)
+        }
+    }
+
+    /// Describes what is watched, for messages
+    pub fn describe(&self) -> String {
+        match self.group {
+            Some(ref name) => format!("group {name}"),
+            None if self.is_file => format!("file {:?}", self.target_dir),
+            None => format!("directory {:?}", self.target_dir),
+        }
+    }
+
     pub fn iter_oids(&self) -> Result<Vec<Result<Oid, git2::Error>>, Error> {
-        self.repo.set_workdir(&self.target_dir, false)?;
+        self.repo
+            .set_workdir(worktree_dir(&self.target_dir, self.is_file), false)?;
         let mut revwalk = self.repo.revwalk()?;
         revwalk.push_ref("refs/heads/main")?;
         revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
@@ -243,7 +980,8 @@ impl WatchDir {
     }
 
     pub fn iter_commits(&self) -> Result<Vec<Result<Commit, git2::Error>>, Error> {
-        self.repo.set_workdir(&self.target_dir, false)?;
+        self.repo
+            .set_workdir(worktree_dir(&self.target_dir, self.is_file), false)?;
         let mut revwalk = self.repo.revwalk()?;
         match revwalk.push_ref("refs/heads/main") {
             Ok(_) => {
@@ -292,6 +1030,43 @@ impl WatchDir {
             )))
     }
 
+    /// Removes a leftover `index.lock` from the snapshot repo. Only call this when no snapshot of
+    /// the directory can be in progress. Returns whether a lock was removed
+    pub fn release_index_lock(&self) -> Result<bool, Error> {
+        let lock_path = self.repo.path().join("index.lock");
+        if lock_path.exists() {
+            fs::remove_file(&lock_path)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Checks that every object of the snapshot repo can be read back (which verifies its hash)
+    /// and that HEAD resolves to a commit, unless no snapshot was taken yet. Returns the number
+    /// of objects checked
+    pub fn verify_repo(&self) -> Result<usize, Error> {
+        let odb = self.repo.odb()?;
+        let mut oids = Vec::new();
+        odb.foreach(|oid| {
+            oids.push(*oid);
+            true
+        })?;
+        for oid in oids.iter() {
+            odb.read(*oid)
+                .map_err(|err| Error::msg(format!("object {oid} is corrupt: {err}")))?;
+        }
+
+        match self.repo.head() {
+            Ok(head) => {
+                head.peel_to_commit()?.tree()?;
+            }
+            Err(err) if err.code() == UnbornBranch => {}
+            Err(err) => return Err(err.into()),
+        }
+        Ok(oids.len())
+    }
+
     pub fn get_repo(&self) -> &Repository {
         &self.repo
     }
@@ -307,38 +1082,722 @@ impl<'de> Deserialize<'de> for WatchDir {
         D: Deserializer<'de>,
     {
         let helper = WatchDirHelper::deserialize(deserializer)?;
+        // Before opening the repo, whose errors about a missing worktree are less helpful
+        if let Some(missing) = helper.missing_target() {
+            return Err(serde::de::Error::custom(missing));
+        }
         let repo = Repository::open(&helper.dotgit_dir).map_err(serde::de::Error::custom)?;
-        repo.set_workdir(&helper.target_dir, false)
+        repo.set_workdir(worktree_dir(&helper.target_dir, helper.is_file), false)
             .map_err(serde::de::Error::custom)?;
+        // Also fills in the key for repos created before it was recorded
+        record_target_dir(&repo, &helper.target_dir);
 
-        Ok(WatchDir {
+        let mut watch_dir = WatchDir {
             target_dir: helper.target_dir,
             dotgit_dir: helper.dotgit_dir,
+            is_file: helper.is_file,
+            group: helper.group,
+            roots: helper.roots,
+            last_snapshot_time: Cell::new(head_commit_time(&repo).unwrap_or(time::UNIX_EPOCH)),
             repo,
             frequency: helper.frequency,
-            last_snapshot_time: Instant::cell_default(),
             max_file_size: helper.max_file_size,
-        })
+            identity: helper.identity,
+            ignores: helper.ignores,
+            retention: helper.retention,
+            pause: helper.pause,
+            include_git_metadata: helper.include_git_metadata,
+            defaults: GlobalSettings::default(),
+            nested_roots: Vec::new(),
+            ignore_matcher: Gitignore::empty(),
+        };
+        watch_dir
+            .apply_ignore_rules()
+            .map_err(serde::de::Error::custom)?;
+
+        Ok(watch_dir)
     }
 }
 
 impl Display for WatchDir {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(
-            f,
-            "{} checked every {}",
-            self.target_dir.display(),
-            format_duration(self.frequency)
-        )
+        match self.group {
+            Some(ref name) => {
+                let roots: Vec<String> = self
+                    .roots
+                    .iter()
+                    .map(|root| format!("{}: {}", root.prefix, root.path.display()))
+                    .collect();
+                write!(f, "group {name} ({})", roots.join(", "))?;
+            }
+            None => write!(f, "{}", self.target_dir.display())?,
+        }
+        write!(f, " checked every {}", format_duration(self.frequency))?;
+        if self.max_file_size != u64::MAX {
+            write!(f, ", files up to {} bytes", self.max_file_size)?;
+        }
+        if !self.ignores.is_empty() {
+            write!(f, ", ignoring {}", self.ignores.join(" "))?;
+        }
+        if let Some(retention) = self.retention {
+            write!(f, ", keeping snapshots for {}", format_duration(retention))?;
+        }
+        if self.include_git_metadata {
+            write!(f, ", including .git metadata")?;
+        }
+        if let Some(pause) = self.active_pause() {
+            write!(f, ", {pause}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Name `.git` entries are stored under in snapshots, as git trees can't contain `.git`
+const GIT_METADATA_NAME: &str = ".timem-dotgit";
+
+/// Collects the index entries of a snapshot while walking the watched directory
+struct Staging<'a> {
+    /// The previous index, used to skip hashing unchanged files
+    index: &'a Index,
+    entries: Vec<IndexEntry>,
+    repos: Vec<NestedRepo>,
+}
+
+/// A git repo inside the watched directory (possibly the directory itself), recorded in the
+/// snapshot message
+struct NestedRepo {
+    /// Location relative to the watched directory, empty for the directory itself
+    path: PathBuf,
+    /// Checked out branch, `None` if HEAD is detached
+    branch: Option<String>,
+    head: Option<Oid>,
+}
+
+impl NestedRepo {
+    fn open(dir: &Path, stored_dir: &Path) -> Option<Self> {
+        let repo = Repository::open(dir)
+            .map_err(|err| log::debug!("Failed to open nested repo {:?}: {err}", dir))
+            .ok()?;
+        let (branch, head) = match repo.head() {
+            Ok(head) => (
+                head.is_branch()
+                    .then(|| head.shorthand().map(String::from))
+                    .flatten(),
+                head.target(),
+            ),
+            // Unborn branch, HEAD names a branch without commits
+            Err(_) => (
+                repo.find_reference("HEAD")
+                    .ok()
+                    .and_then(|head| head.symbolic_target().map(String::from))
+                    .map(|target| target.trim_start_matches("refs/heads/").to_owned()),
+                None,
+            ),
+        };
+        Some(Self {
+            path: stored_dir.to_owned(),
+            branch,
+            head,
+        })
+    }
+}
+
+impl Display for NestedRepo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let path = if self.path.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            &self.path
+        };
+        write!(f, "{}: ", path.display())?;
+        match self.branch {
+            Some(ref branch) => write!(f, "branch {branch}")?,
+            None => write!(f, "detached HEAD")?,
+        }
+        match self.head {
+            Some(head) => write!(f, " at {head}"),
+            None => write!(f, ", no commits"),
+        }
     }
 }
 
-pub trait CellDefault {
-    fn cell_default() -> Cell<Self>;
+/// Snapshot commit message, listing the branch and HEAD of the git repos in the directory
+fn snapshot_message(time: Duration, repos: &[NestedRepo]) -> String {
+    let mut message = format!("TimeM snapshot at {:?}", time);
+    if !repos.is_empty() {
+        message.push('\n');
+        for repo in repos {
+            message.push_str(&format!("\nGit-Repo: {repo}"));
+        }
+    }
+    message
+}
+
+/// Moves the `.git` metadata checked out from `tree` into place. Where a `.git` already exists
+/// it is kept, and the restored copy removed
+fn restore_git_metadata(tree: &Tree, restore_to: &Path) -> Result<(), Error> {
+    let mut metadata_paths = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.name_bytes() == GIT_METADATA_NAME.as_bytes() {
+            metadata_paths.push(restore_to.join(root).join(GIT_METADATA_NAME));
+            return TreeWalkResult::Skip;
+        }
+        TreeWalkResult::Ok
+    })?;
+
+    for restored in metadata_paths {
+        let dotgit = restored.with_file_name(".git");
+        if dotgit.exists() {
+            log::info!("Keeping existing {:?}, not restoring its snapshot", dotgit);
+            if restored.is_dir() {
+                fs::remove_dir_all(&restored)?;
+            } else {
+                fs::remove_file(&restored)?;
+            }
+        } else {
+            fs::rename(&restored, &dotgit)?;
+            if dotgit.is_dir() {
+                // Snapshots don't keep empty directories, but git needs these to find the repo
+                for required in ["objects", "refs/heads", "refs/tags"] {
+                    fs::create_dir_all(dotgit.join(required))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
 }
 
-impl CellDefault for Instant {
-    fn cell_default() -> Cell<Self> {
-        Cell::new(Instant::now())
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(if executable {
+        mode | (mode & 0o444) >> 2
+    } else {
+        mode & !0o111
+    });
+    Ok(fs::set_permissions(path, permissions)?)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn restore_symlink(target: &[u8], path: &Path) -> Result<(), Error> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::os::unix::fs::symlink(OsStr::from_bytes(target), path)?)
+}
+
+#[cfg(not(unix))]
+fn restore_symlink(_target: &[u8], path: &Path) -> Result<(), Error> {
+    Err(Error::msg(format!(
+        "Can't restore {:?}, symlinks are not supported on this platform",
+        path
+    )))
+}
+
+/// Checks that a group name can be used in its repo name, and that every root is a directory
+/// with a prefix of its own. Roots can't nest, or their files would be snapshotted twice
+fn validate_group(name: &str, roots: &[GroupRoot]) -> Result<(), Error> {
+    let valid_name = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid_name {
+        return Err(Error::msg(format!(
+            "Invalid group name {name:?}, use letters, digits, '-', '_' and '.'"
+        )));
+    }
+    if roots.is_empty() {
+        return Err(Error::msg("A group needs at least one directory"));
+    }
+
+    for (i, root) in roots.iter().enumerate() {
+        let prefix = Path::new(&root.prefix);
+        let single_component = prefix.components().count() == 1
+            && prefix.file_name() == Some(OsStr::new(&root.prefix));
+        if !single_component || root.prefix == ".git" || root.prefix == GIT_METADATA_NAME {
+            return Err(Error::msg(format!("Invalid prefix {:?}", root.prefix)));
+        }
+        if !root.path.is_dir() {
+            return Err(Error::msg(format!("{:?} is not a directory", root.path)));
+        }
+        for other in roots[..i].iter() {
+            if other.prefix == root.prefix {
+                return Err(Error::msg(format!(
+                    "{:?} and {:?} both use the prefix {:?}, set one with prefix=path",
+                    other.path, root.path, root.prefix
+                )));
+            }
+            if other.path.starts_with(&root.path) || root.path.starts_with(&other.path) {
+                return Err(Error::msg(format!(
+                    "{:?} and {:?} overlap",
+                    other.path, root.path
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the tree stored under `name` in `tree`
+fn tree_at<'repo>(repo: &'repo Repository, tree: &Tree, name: &str) -> Option<Tree<'repo>> {
+    let entry = tree.get_name(name)?;
+    entry.to_object(repo).ok()?.into_tree().ok()
+}
+
+/// Lists the files (and symlinks) in `tree` recursively, with their blob and mode
+fn tree_files(tree: &Tree) -> Result<Vec<(PathBuf, Oid, i32)>, Error> {
+    let mut files = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() == Some(ObjectType::Blob) {
+            let path = Path::new(root).join(entry.name_bytes().to_path_lossy());
+            files.push((path, entry.id(), entry.filemode()));
+        }
+        TreeWalkResult::Ok
+    })?;
+    Ok(files)
+}
+
+/// Directory the snapshot repo's worktree points to, the parent directory for watched files
+fn worktree_dir(target_dir: &Path, is_file: bool) -> &Path {
+    match target_dir.parent() {
+        Some(parent) if is_file => parent,
+        _ => target_dir,
+    }
+}
+
+/// Creates the snapshot repo of `target_dir` directly at `dotgit_dir`. The repo is initialized
+/// bare and pointed at `target_dir` through `core.worktree`, so nothing is ever written to the
+/// watched directory (libgit2 would otherwise leave a `.git` gitlink file there)
+fn init_repo(dotgit_dir: &Path, target_dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dotgit_dir)?;
+    let mut opts = RepositoryInitOptions::new();
+    opts.external_template(true).bare(true);
+    let repo = Repository::init_opts(dotgit_dir, &opts)?;
+
+    let mut config = repo.config()?.open_level(ConfigLevel::Local)?;
+    config.set_bool("core.bare", false)?;
+    if let Some(target_dir) = target_dir.to_str() {
+        config.set_str("core.worktree", target_dir)?;
+    }
+    Ok(())
+}
+
+/// Repo config key holding the directory a snapshot repo belongs to, as hashed repo names can't
+/// be decoded back to it
+pub(crate) const TARGET_DIR_CONFIG_KEY: &str = "timem.targetdir";
+/// Repo config key holding the name of the group a snapshot repo belongs to
+pub(crate) const GROUP_CONFIG_KEY: &str = "timem.group";
+
+/// Makes `commit` the root of the history, like the boundary of a shallow clone, by replacing the
+/// repo's `shallow` file. Git and libgit2 then ignore its parents
+fn write_shallow_graft(repo: &Repository, commit: Oid) -> Result<(), Error> {
+    let shallow_path = repo.path().join("shallow");
+    let tmp_path = repo.path().join("shallow.tmp");
+    fs::write(&tmp_path, format!("{commit}\n"))?;
+    fs::rename(&tmp_path, &shallow_path)?;
+    Ok(())
+}
+
+/// Stores `target_dir` under `TARGET_DIR_CONFIG_KEY` in the repo config, if it isn't already
+fn record_target_dir(repo: &Repository, target_dir: &Path) {
+    // Non UTF-8 paths can't be stored in git config, their repo names are decoded instead
+    let Some(target_dir) = target_dir.to_str() else {
+        return;
+    };
+    let result = repo
+        .config()
+        .and_then(|config| config.open_level(ConfigLevel::Local))
+        .and_then(|mut config| {
+            if config.get_string(TARGET_DIR_CONFIG_KEY).ok().as_deref() == Some(target_dir) {
+                return Ok(());
+            }
+            config.set_str(TARGET_DIR_CONFIG_KEY, target_dir)
+        });
+    if let Err(err) = result {
+        log::warn!(
+            "Failed to record {:?} in the config of its snapshot repo: {err}",
+            target_dir
+        );
+    }
+}
+
+/// Longest file name most filesystems accept (`NAME_MAX`)
+const MAX_DOTGIT_NAME_LEN: usize = 255;
+/// Longest readable prefix kept in hashed dotgit names, leaving room for the hash
+const MAX_HASHED_NAME_PREFIX_LEN: usize = 180;
+
+/// Name of the snapshot repo of `target_dir` in the `.git_dirs` directory: `_` is escaped as `__`
+/// and path separators as `_d_`. Names that would be too long for the filesystem are made of the
+/// longest ancestor of `target_dir` that fits, then `_h_` and the SHA-256 of the full path
+pub(crate) fn encode_dotgit_name(target_dir: &Path) -> OsString {
+    let escaped = escape_path(target_dir);
+    if escaped.len() <= MAX_DOTGIT_NAME_LEN {
+        return escaped;
+    }
+
+    let prefix = target_dir
+        .ancestors()
+        .map(escape_path)
+        .find(|prefix| prefix.len() <= MAX_HASHED_NAME_PREFIX_LEN)
+        .unwrap_or_default();
+    let hash = Sha256::digest(target_dir.as_os_str().as_encoded_bytes());
+    let mut name = prefix;
+    name.push(format!("_h_{hash:x}"));
+    name
+}
+
+fn escape_path(path: &Path) -> OsString {
+    let escaped = path
+        .as_os_str()
+        .as_encoded_bytes()
+        .replace(b"_", b"__")
+        .replace(MAIN_SEPARATOR.to_string(), b"_d_");
+    // Only ASCII bytes were replaced, so the result is still valid
+    unsafe { OsStr::from_encoded_bytes_unchecked(escaped.as_slice()) }.to_owned()
+}
+
+/// Reverses `encode_dotgit_name`, returning `None` for hashed names and names it can't have
+/// produced
+pub(crate) fn decode_dotgit_name(name: &OsStr) -> Option<PathBuf> {
+    let encoded = name.as_encoded_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        match &encoded[i..] {
+            [b'_', b'_', ..] => {
+                decoded.push(b'_');
+                i += 2;
+            }
+            [b'_', b'd', b'_', ..] => {
+                decoded.extend_from_slice(MAIN_SEPARATOR.to_string().as_bytes());
+                i += 3;
+            }
+            [b'_', ..] => return None,
+            [byte, ..] => {
+                decoded.push(*byte);
+                i += 1;
+            }
+            [] => unreachable!(),
+        }
+    }
+    // Only ever split at ASCII characters of valid encoded bytes, so the result stays valid
+    Some(PathBuf::from(unsafe {
+        OsStr::from_encoded_bytes_unchecked(&decoded)
+    }))
+}
+
+/// Returns the committer time of the repo's HEAD commit, if there is one
+pub(crate) fn head_commit_time(repo: &Repository) -> Option<SystemTime> {
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    let seconds = u64::try_from(commit.committer().when().seconds()).ok()?;
+    time::UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+fn fallback_signature() -> Result<Signature<'static>, git2::Error> {
+    let hostname = hostname::get()
+        .ok()
+        .and_then(|name| name.into_string().ok())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".into());
+    Signature::now("TimeM", &format!("timem@{hostname}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::{write, ScratchDir};
+
+    #[test]
+    fn hashes_long_names() {
+        let long_dir = PathBuf::from("/home")
+            .join("x".repeat(150))
+            .join("y".repeat(150));
+        let name = encode_dotgit_name(&long_dir);
+        assert!(name.len() <= 255);
+        assert!(name.to_str().unwrap().starts_with("_d_home_d_xxx"));
+        assert_eq!(decode_dotgit_name(&name), None);
+
+        let sibling = long_dir.with_file_name("z".repeat(100));
+        assert_ne!(encode_dotgit_name(&sibling), name);
+    }
+
+    fn staged_paths(watch_dir: &WatchDir) -> Vec<String> {
+        let (index, _) = watch_dir.stage().unwrap();
+        index_paths(&index)
+    }
+
+    fn index_paths(index: &Index) -> Vec<String> {
+        let mut paths: Vec<_> = index
+            .iter()
+            .map(|entry| String::from_utf8(entry.path).unwrap())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn stages_nested_repo_worktree_without_metadata() {
+        let scratch = ScratchDir::new("stage-nested");
+        let (home, project) = (scratch.home(), scratch.dir("project"));
+        write(&project.join("lib/src.rs"), "fn main() {}");
+        write(&project.join("lib/.git/HEAD"), "ref: refs/heads/main\n");
+        let mut watch_dir =
+            WatchDir::new(&home, project.clone(), Duration::from_secs(60), 0).unwrap();
+
+        assert_eq!(staged_paths(&watch_dir), ["lib/src.rs"]);
+
+        watch_dir.set_include_git_metadata(true);
+        assert_eq!(
+            staged_paths(&watch_dir),
+            [
+                format!("lib/{GIT_METADATA_NAME}/HEAD").as_str(),
+                "lib/src.rs"
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_only_configured_ignores() {
+        let scratch = ScratchDir::new("stage-ignores");
+        let (home, project) = (scratch.home(), scratch.dir("project"));
+        write(&project.join(".gitignore"), "secret.env\n");
+        write(&project.join("secret.env"), "KEY=1");
+        write(&project.join("run.log"), "log");
+        write(&project.join("build/out.o"), "obj");
+        write(&project.join("src/build.rs"), "fn main() {}");
+        let mut watch_dir =
+            WatchDir::new(&home, project.clone(), Duration::from_secs(60), 0).unwrap();
+        watch_dir
+            .set_ignores(vec!["*.log".to_owned(), "build/".to_owned()])
+            .unwrap();
+
+        assert_eq!(
+            staged_paths(&watch_dir),
+            [".gitignore", "secret.env", "src/build.rs"]
+        );
+    }
+
+    #[test]
+    fn skips_files_over_size_limit() {
+        let scratch = ScratchDir::new("stage-size");
+        let (home, project) = (scratch.home(), scratch.dir("project"));
+        write(&project.join("small.txt"), "tiny");
+        write(&project.join("large.bin"), &"x".repeat(64));
+        let watch_dir = WatchDir::new(&home, project.clone(), Duration::from_secs(60), 16).unwrap();
+
+        assert_eq!(staged_paths(&watch_dir), ["small.txt"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_unreadable_dirs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let scratch = ScratchDir::new("stage-unreadable");
+        let (home, project) = (scratch.home(), scratch.dir("project"));
+        write(&project.join("readable.txt"), "text");
+        write(&project.join("locked/secret.txt"), "secret");
+        let watch_dir = WatchDir::new(&home, project.clone(), Duration::from_secs(60), 0).unwrap();
+
+        let locked = project.join("locked");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // Root reads it regardless, leaving nothing to test
+        let readable_anyway = fs::read_dir(&locked).is_ok();
+        let staged = watch_dir.stage().map(|(index, _)| index_paths(&index));
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+        if !readable_anyway {
+            assert_eq!(staged.unwrap(), ["readable.txt"]);
+        }
+    }
+
+    #[test]
+    fn first_snapshot_is_due_at_once() {
+        let scratch = ScratchDir::new("schedule-first");
+        let (home, project) = (scratch.home(), scratch.dir("project"));
+        write(&project.join("file.txt"), "text");
+        let day = Duration::from_secs(24 * 60 * 60);
+        let watch_dir = WatchDir::new(&home, project.clone(), day, 0).unwrap();
+
+        assert_eq!(
+            watch_dir.snapshot(false).unwrap(),
+            SnapshotOutcome::Committed
+        );
+    }
+
+    #[test]
+    fn schedule_follows_last_snapshot_across_restarts() {
+        let scratch = ScratchDir::new("schedule-restart");
+        let (home, project) = (scratch.home(), scratch.dir("project"));
+        write(&project.join("file.txt"), "text");
+        let day = Duration::from_secs(24 * 60 * 60);
+        let watch_dir = WatchDir::new(&home, project.clone(), day, 0).unwrap();
+        watch_dir.snapshot(false).unwrap();
+        let dotgit_dir = watch_dir.dotgit_dir.clone();
+        drop(watch_dir);
+
+        write(&project.join("file.txt"), "changed");
+        let reopened = WatchDir::open(dotgit_dir, project, day, 0).unwrap();
+        assert_eq!(reopened.snapshot(false).unwrap(), SnapshotOutcome::NotDue);
+    }
+
+    /// Commits the current contents of the watched directory as a snapshot taken `age` ago
+    fn commit_aged(watch_dir: &WatchDir, age: Duration) -> Oid {
+        let (mut index, _) = watch_dir.stage().unwrap();
+        index.write().unwrap();
+        let tree = watch_dir
+            .repo
+            .find_tree(index.write_tree().unwrap())
+            .unwrap();
+        let seconds = (SystemTime::now() - age)
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let signature = Signature::new(
+            "Test",
+            "test@example.com",
+            &git2::Time::new(seconds as i64, 0),
+        )
+        .unwrap();
+        let parents: Vec<Commit> = watch_dir.get_head_commit().into_iter().collect();
+        watch_dir
+            .repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                "snapshot",
+                &tree,
+                &parents.iter().collect::<Vec<_>>(),
+            )
+            .unwrap()
+    }
+
+    /// Checks that `commit` is the only one left in a freshly opened repo, with all its files
+    fn assert_only_commit(watch_dir: &WatchDir, commit: Oid, dropped: &[Oid]) {
+        let repo = Repository::open(&watch_dir.dotgit_dir).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.id(), commit);
+        assert!(head.parent(0).is_err());
+        head.tree()
+            .unwrap()
+            .walk(TreeWalkMode::PreOrder, |_, entry| {
+                assert!(repo.find_object(entry.id(), None).is_ok());
+                TreeWalkResult::Ok
+            })
+            .unwrap();
+        for id in dropped {
+            assert!(repo.find_commit(*id).is_err());
+        }
+    }
+
+    #[test]
+    fn prunes_old_snapshots_keeping_hashes() {
+        let scratch = ScratchDir::new("prune");
+        let (home, project) = (scratch.home(), scratch.dir("project"));
+        let hour = Duration::from_secs(60 * 60);
+        let mut watch_dir = WatchDir::new(&home, project.clone(), hour, 0).unwrap();
+        watch_dir.set_retention(Some(hour));
+        assert_eq!(watch_dir.prune_snapshots().unwrap(), 0);
+
+        let mut old = Vec::new();
+        for hours in [4, 3, 2] {
+            write(&project.join("file.txt"), &format!("{hours} hours old"));
+            old.push(commit_aged(&watch_dir, hour * hours));
+        }
+        write(&project.join("file.txt"), "recent");
+        let recent = commit_aged(&watch_dir, hour / 2);
+
+        assert_eq!(watch_dir.prune_snapshots().unwrap(), 3);
+        assert_only_commit(&watch_dir, recent, &old);
+
+        write(&project.join("file.txt"), "latest");
+        assert_eq!(
+            watch_dir.snapshot(true).unwrap(),
+            SnapshotOutcome::Committed
+        );
+        let latest = {
+            let commit = watch_dir.get_head_commit().unwrap();
+            assert_eq!(commit.parent_id(0).unwrap(), recent);
+            commit.id()
+        };
+
+        watch_dir.set_retention(Some(hour / 4));
+        assert_eq!(watch_dir.prune_snapshots().unwrap(), 1);
+        assert_only_commit(&watch_dir, latest, &[recent]);
+    }
+
+    /// Lists the files under `dir` with their contents, sorted by path
+    fn dir_contents(dir: &Path) -> Vec<(PathBuf, String)> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.extend(dir_contents(&path));
+            } else {
+                files.push((path.clone(), fs::read_to_string(&path).unwrap()));
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn restores_every_group_root_to_the_same_snapshot() {
+        let scratch = ScratchDir::new("restore-group");
+        let home = scratch.home();
+        let (docs, code) = (scratch.dir("docs"), scratch.dir("code"));
+        write(&docs.join("notes.txt"), "first notes");
+        write(&docs.join("drafts/plan.txt"), "plan");
+        write(&code.join("main.rs"), "fn main() {}");
+        write(&code.join("old.rs"), "// old");
+        let roots = vec![
+            GroupRoot {
+                prefix: "docs".to_owned(),
+                path: docs.clone(),
+            },
+            GroupRoot {
+                prefix: "code".to_owned(),
+                path: code.clone(),
+            },
+        ];
+        let hour = Duration::from_secs(60 * 60);
+        let watch_dir = WatchDir::new_group(&home, "work", roots, hour, 0).unwrap();
+        watch_dir.snapshot(true).unwrap();
+        let first = watch_dir.get_head_commit().unwrap().id();
+        let (docs_before, code_before) = (dir_contents(&docs), dir_contents(&code));
+
+        write(&docs.join("notes.txt"), "second notes");
+        write(&docs.join("todo.txt"), "todo");
+        fs::remove_file(docs.join("drafts/plan.txt")).unwrap();
+        write(&code.join("main.rs"), "fn main() { run() }");
+        write(&code.join("lib.rs"), "fn run() {}");
+        fs::remove_file(code.join("old.rs")).unwrap();
+        assert_eq!(
+            watch_dir.snapshot(true).unwrap(),
+            SnapshotOutcome::Committed
+        );
+
+        let commit = watch_dir.repo.find_commit(first).unwrap();
+        watch_dir.restore_snapshot(commit, None::<&Path>).unwrap();
+        assert_eq!(dir_contents(&docs), docs_before);
+        assert_eq!(dir_contents(&code), code_before);
     }
 }
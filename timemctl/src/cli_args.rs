@@ -1,4 +1,5 @@
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
@@ -6,7 +7,9 @@ use structopt::StructOpt;
 use humantime::parse_duration;
 use parse_size::parse_size;
 
-use crate::WatchDir;
+use crate::{
+    GlobalSettings, GroupRoot, Pause, SnapshotIdentity, TimemHome, WatchDir, WatchPattern,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -14,6 +17,9 @@ use crate::WatchDir;
     global_settings = &[AppSettings::ColoredHelp]
 )]
 pub struct Args {
+    #[structopt(long = "config", global = true, parse(from_os_str))]
+    /// TimeM home directory to use instead of $TIMEM_HOME or the OS config directory
+    pub config_root: Option<PathBuf>,
     #[structopt(subcommand)]
     pub cmd: Command,
 }
@@ -21,11 +27,33 @@ pub struct Args {
 #[derive(Debug, StructOpt)]
 pub enum Command {
     #[structopt(name = "watch")]
-    /// Adds a directory to the watch list
+    /// Adds a directory, or a single file, to the watch list
     Watch(CLIWatch),
+    #[structopt(name = "watch-group")]
+    /// Watches several directories as one named group, snapshotted and restored together
+    WatchGroup(CLIWatchGroup),
+    #[structopt(name = "watch-pattern")]
+    /// Watches every directory matching a glob pattern, including the ones created later
+    WatchPattern(CLIWatchPattern),
+    #[structopt(name = "set")]
+    /// Changes the settings of a watched directory
+    Set(CLISet),
+    #[structopt(name = "pause")]
+    /// Suspends snapshots of a directory, or of every directory (changes keep being tracked)
+    Pause(CLIPause),
+    #[structopt(name = "resume")]
+    /// Resumes snapshots of a paused directory, or of every directory
+    Resume(CLIResume),
     #[structopt(name = "list")]
     /// Lists all watched directories
     List,
+    #[structopt(name = "status")]
+    /// Shows the snapshot status (last snapshot, errors, quarantine) of every watched directory
+    Status,
+    #[structopt(name = "doctor")]
+    /// Checks the config, watched directories and daemon for problems, suggesting fixes
+    /// (exits with 1 if any problem was found)
+    Doctor,
     #[structopt(name = "log")]
     /// List all snapshots for a directory
     Log(CLILog),
@@ -35,27 +63,84 @@ pub enum Command {
     #[structopt(name = "restore")]
     /// Restore a snapshot
     Restore(CLIRestore),
-    /// Completely removes config file (*warning*, this unwatches all watched directories)
-    ClearConf,
+    #[structopt(name = "recover")]
+    /// Re-adds watched directories from snapshot repos that no config entry points to (e.g.
+    /// after clear-conf)
+    Recover(CLIRecover),
+    #[structopt(name = "gc-orphans")]
+    /// Lists snapshot repos that no config entry points to, and deletes, archives or re-adopts
+    /// them
+    GcOrphans(CLIGcOrphans),
+    /// Unwatches all watched directories, after backing up the config (see `config restore-backup`)
+    ClearConf(CLIClearConf),
+    #[structopt(name = "config")]
+    /// Converts between config.json and the TOML config format
+    Config(CLIConfig),
+    #[structopt(name = "service")]
+    /// Manages the timemserv systemd user service
+    Service(CLIService),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum CLIConfig {
+    #[structopt(name = "export")]
+    /// Prints the global settings and watched directories as TOML
+    Export {
+        #[structopt(short, long)]
+        /// Write the TOML to this file instead of stdout
+        output: Option<String>,
+    },
+    #[structopt(name = "import")]
+    /// Applies a TOML config to config.json (watched directories it doesn't list are kept)
+    Import {
+        #[structopt()]
+        /// The TOML file to import (defaults to config.toml in the config directory)
+        file: Option<String>,
+    },
+    #[structopt(name = "restore-backup")]
+    /// Restores a config backup made by clear-conf (the current config is backed up first)
+    RestoreBackup {
+        #[structopt()]
+        /// The backup to restore, by path or file name (defaults to the latest)
+        backup: Option<String>,
+        #[structopt(short, long)]
+        /// Lists the available backups instead
+        list: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum CLIService {
+    #[structopt(name = "install")]
+    /// Generates a systemd user unit for timemserv, then enables and starts it
+    Install {
+        #[structopt(long)]
+        /// Location of the timemserv binary (defaults to next to timemctl, then $PATH)
+        timemserv_path: Option<String>,
+    },
+    #[structopt(name = "uninstall")]
+    /// Stops, disables and removes the timemserv systemd user unit
+    Uninstall,
 }
 
 #[derive(Debug, StructOpt)]
 pub struct CLIRestore {
     #[structopt()]
-    /// The snapshotted directory
+    /// The snapshotted directory or file
     pub dir: String,
     #[structopt()]
     /// The snapshot hash to restore
     pub hash: String,
     #[structopt(short, long)]
     /// If provided, the snapshot will be restored to this directory instead of the snapshot origin directory
+    /// (for a watched file, this can also be the file path to write)
     pub to: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
 pub struct CLIDiff {
     #[structopt(short, long, default_value = ".")]
-    /// The directory to diff
+    /// The directory or file to diff
     pub dir: String,
     #[structopt()]
     /// An initial snapshot hash to diff
@@ -68,8 +153,8 @@ pub struct CLIDiff {
 #[derive(Debug, StructOpt)]
 pub struct CLIWatch {
     #[structopt()]
-    /// The directory to add to the watch list
-    dir: String,
+    /// The directory or file to add to the watch list (files get a snapshot repo of their own)
+    pub dir: String,
     #[structopt()]
     /// How often (e.g., 1h30m, 1d, 5m30s, etc.) to automatically take a snapshot of the directory
     /// (snapshots are only take if files have changed)
@@ -78,17 +163,154 @@ pub struct CLIWatch {
     /// Max file size to sync inside the folder (files above this size will not be snapshotted).
     /// The default is 0, meaning all files will be snapshotted (e.g., 0.2 MiB, 2G, 128kb, etc.)
     max_file_size: Option<String>,
+    #[structopt(long, requires = "author-email")]
+    /// Author name used for snapshot commits (defaults to the git config, or "TimeM")
+    author_name: Option<String>,
+    #[structopt(long, requires = "author-name")]
+    /// Author email used for snapshot commits (defaults to the git config, or timem@<hostname>)
+    author_email: Option<String>,
+    #[structopt(long)]
+    /// Also snapshot the .git directories of the directory and of git repos nested in it (their
+    /// worktrees are always snapshotted as plain files)
+    include_git_metadata: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CLIWatchGroup {
+    #[structopt()]
+    /// Name of the group (letters, digits, '-', '_' and '.'). Use it in place of a directory in
+    /// the other commands
+    pub name: String,
+    #[structopt()]
+    /// How often (e.g., 1h30m, 1d, 5m30s, etc.) to automatically take a snapshot of the group
+    frequency: String,
+    #[structopt(required = true)]
+    /// The directories of the group, as `path` or `prefix=path`. Snapshots store each directory
+    /// under its prefix, which defaults to the directory name
+    roots: Vec<String>,
+    #[structopt(short, long)]
+    /// Max file size to sync inside the directories (e.g., 0.2 MiB, 2G, 128kb, etc.)
+    max_file_size: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CLIWatchPattern {
+    #[structopt()]
+    /// Glob pattern of the directories to watch, e.g. '~/projects/*' (quote it so the shell
+    /// doesn't expand it)
+    pattern: String,
+    #[structopt()]
+    /// How often (e.g., 1h30m, 1d, 5m30s, etc.) to automatically take a snapshot of each
+    /// directory
+    frequency: String,
+    #[structopt(short, long)]
+    /// Max file size to sync inside the directories (e.g., 0.2 MiB, 2G, 128kb, etc.)
+    max_file_size: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CLISet {
+    #[structopt()]
+    /// The watched directory to change
+    pub dir: String,
+    #[structopt(short, long)]
+    /// New snapshot frequency (e.g., 1h30m, 1d, 5m30s, etc.)
+    frequency: Option<String>,
+    #[structopt(short, long)]
+    /// New max file size to sync (e.g., 0.2 MiB, 2G, 128kb, etc.). 0 means no limit
+    max_file_size: Option<String>,
+    #[structopt(long = "ignore", number_of_values = 1)]
+    /// Adds a gitignore-style pattern to exclude from snapshots (can be repeated)
+    ignores: Vec<String>,
+    #[structopt(long)]
+    /// Removes all the directory's ignore patterns (applied before --ignore)
+    clear_ignores: bool,
+    #[structopt(long)]
+    /// How long to keep snapshots (e.g., 30d), or "forever"
+    retention: Option<String>,
+    #[structopt(long)]
+    /// Whether to also snapshot .git directories (true or false)
+    include_git_metadata: Option<bool>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CLIPause {
+    #[structopt()]
+    /// The watched directory to pause (if not provided, all directories are paused)
+    pub dir: Option<String>,
+    #[structopt(long = "for")]
+    /// Resume automatically after this long (e.g., 30m, 2h, etc.)
+    duration: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CLIResume {
+    #[structopt()]
+    /// The watched directory to resume (if not provided, all directories are resumed)
+    pub dir: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CLIRecover {
+    #[structopt(short, long)]
+    /// Recover every repo without asking
+    pub all: bool,
+    #[structopt(short, long)]
+    /// Snapshot frequency of the recovered directories (defaults to the global frequency)
+    frequency: Option<String>,
+    #[structopt(short, long)]
+    /// Max file size of the recovered directories (defaults to the global max file size)
+    max_file_size: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CLIGcOrphans {
+    #[structopt()]
+    /// Repos to act on, by repo path, repo name or original directory (defaults to all orphans)
+    pub repos: Vec<String>,
+    #[structopt(long, conflicts_with_all = &["archive", "adopt"])]
+    /// Deletes the repos and their snapshots
+    pub delete: bool,
+    #[structopt(long, parse(from_os_str), conflicts_with = "adopt")]
+    /// Writes each repo as a git bundle into this directory, then deletes it
+    pub archive: Option<PathBuf>,
+    #[structopt(long)]
+    /// Watches the original directories again, keeping their snapshots
+    pub adopt: bool,
+    #[structopt(long, requires = "adopt", parse(from_os_str))]
+    /// With --adopt, watch this directory instead of the original one (e.g. after a rename)
+    pub to: Option<PathBuf>,
+    #[structopt(short, long)]
+    /// With --adopt, snapshot frequency (defaults to the global frequency)
+    frequency: Option<String>,
+    #[structopt(short, long)]
+    /// With --adopt, max file size (defaults to the global max file size)
+    max_file_size: Option<String>,
+    #[structopt(short, long)]
+    /// Delete without asking for confirmation
+    pub yes: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CLIClearConf {
+    #[structopt(short, long)]
+    /// Don't ask for confirmation
+    pub yes: bool,
+    #[structopt(long)]
+    /// Also delete the snapshot repos of every directory (*warning*, this deletes all snapshots)
+    pub purge_repos: bool,
 }
 
 #[derive(Debug, StructOpt)]
 pub struct CLILog {
     #[structopt(short, long, default_value = ".")]
-    /// The directory for which list snapshots
+    /// The directory or file for which list snapshots
     pub dir: String,
 }
 
-impl From<CLIWatch> for Result<WatchDir, String> {
-    fn from(value: CLIWatch) -> Self {
+impl CLIWatch {
+    pub fn into_watch_dir(self, home: &TimemHome) -> Result<WatchDir, String> {
+        let value = self;
         let dir = Path::new(&value.dir)
             .canonicalize()
             .unwrap_or(PathBuf::from(value.dir));
@@ -96,8 +318,11 @@ impl From<CLIWatch> for Result<WatchDir, String> {
         if !dir.exists() {
             return Err("Path doesn't exist".into());
         }
-        if !dir.is_dir() {
-            return Err("Path is not a directory".into());
+        if !dir.is_dir() && !dir.is_file() {
+            return Err("Path is not a directory or a regular file".into());
+        }
+        if dir.is_file() && value.include_git_metadata {
+            return Err("--include-git-metadata only applies to directories".into());
         }
 
         let frequency = parse_duration(&value.frequency).map_err(|err| err.to_string())?;
@@ -105,6 +330,157 @@ impl From<CLIWatch> for Result<WatchDir, String> {
         let max_file_size = parse_size(value.max_file_size.unwrap_or("0B".into()))
             .map_err(|err| err.to_string())?;
 
-        Ok(WatchDir::new(dir, frequency, max_file_size).map_err(|err| err.to_string())?)
+        let mut watch_dir =
+            WatchDir::new(home, dir, frequency, max_file_size).map_err(|err| err.to_string())?;
+        if let (Some(name), Some(email)) = (value.author_name, value.author_email) {
+            watch_dir.set_identity(Some(SnapshotIdentity { name, email }));
+        }
+        watch_dir.set_include_git_metadata(value.include_git_metadata);
+
+        Ok(watch_dir)
+    }
+}
+
+impl CLIWatchGroup {
+    pub fn into_watch_dir(self, home: &TimemHome) -> Result<WatchDir, String> {
+        let roots = self
+            .roots
+            .iter()
+            .map(|root| {
+                let (prefix, path) = match root.split_once('=') {
+                    Some((prefix, path)) => (Some(prefix), path),
+                    None => (None, root.as_str()),
+                };
+                let path = Path::new(path)
+                    .canonicalize()
+                    .map_err(|err| format!("{path}: {err}"))?;
+                let prefix = match prefix {
+                    Some(prefix) => prefix.to_owned(),
+                    None => path
+                        .file_name()
+                        .ok_or(format!("{:?} has no name, set a prefix=path", path))?
+                        .to_string_lossy()
+                        .into_owned(),
+                };
+                Ok(GroupRoot { prefix, path })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let frequency = parse_duration(&self.frequency).map_err(|err| err.to_string())?;
+        let max_file_size =
+            parse_size(self.max_file_size.unwrap_or("0B".into())).map_err(|err| err.to_string())?;
+
+        WatchDir::new_group(home, &self.name, roots, frequency, max_file_size)
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl CLIWatchPattern {
+    pub fn to_watch_pattern(&self) -> Result<WatchPattern, String> {
+        let frequency = parse_duration(&self.frequency).map_err(|err| err.to_string())?;
+        let max_file_size = parse_size(self.max_file_size.as_deref().unwrap_or("0B"))
+            .map_err(|err| err.to_string())?;
+        WatchPattern::new(&self.pattern, frequency, max_file_size)
+    }
+}
+
+/// 9999-12-31T23:59:59Z
+const LATEST_PAUSE_END_SECS: u64 = 253_402_300_799;
+
+impl CLIPause {
+    pub fn to_pause(&self) -> Result<Pause, String> {
+        let until = match self.duration {
+            Some(ref duration) => {
+                let parsed = parse_duration(duration).map_err(|err| err.to_string())?;
+                // Pause ends are displayed as RFC 3339 timestamps, which end with year 9999
+                let latest = UNIX_EPOCH + Duration::from_secs(LATEST_PAUSE_END_SECS);
+                let until = SystemTime::now()
+                    .checked_add(parsed)
+                    .filter(|until| *until <= latest)
+                    .ok_or(format!("Pause duration {duration} is too long"))?;
+                Some(until)
+            }
+            None => None,
+        };
+        Ok(Pause { until })
+    }
+}
+
+impl CLIRecover {
+    /// Returns the frequency and max file size to watch recovered directories with
+    pub fn settings(&self, defaults: &GlobalSettings) -> Result<(Duration, u64), String> {
+        watch_settings(
+            self.frequency.as_deref(),
+            self.max_file_size.as_deref(),
+            defaults,
+        )
+    }
+}
+
+impl CLIGcOrphans {
+    /// Returns the frequency and max file size to watch adopted directories with
+    pub fn settings(&self, defaults: &GlobalSettings) -> Result<(Duration, u64), String> {
+        watch_settings(
+            self.frequency.as_deref(),
+            self.max_file_size.as_deref(),
+            defaults,
+        )
+    }
+}
+
+/// Parses the frequency and max file size given on the command line, falling back to the global
+/// settings
+fn watch_settings(
+    frequency: Option<&str>,
+    max_file_size: Option<&str>,
+    defaults: &GlobalSettings,
+) -> Result<(Duration, u64), String> {
+    let frequency = match frequency {
+        Some(frequency) => parse_duration(frequency).map_err(|err| err.to_string())?,
+        None => defaults
+            .frequency
+            .ok_or("No global frequency configured, pass --frequency")?,
+    };
+    let max_file_size = match max_file_size {
+        Some(max_file_size) => parse_size(max_file_size).map_err(|err| err.to_string())?,
+        None => defaults.max_file_size.unwrap_or(0),
+    };
+    Ok((frequency, max_file_size))
+}
+
+impl CLISet {
+    pub fn apply_to(self, watch_dir: &mut WatchDir) -> Result<(), String> {
+        if let Some(frequency) = self.frequency {
+            watch_dir.set_frequency(parse_duration(&frequency).map_err(|err| err.to_string())?);
+        }
+        if let Some(max_file_size) = self.max_file_size {
+            watch_dir.set_max_file_size(parse_size(max_file_size).map_err(|err| err.to_string())?);
+        }
+        if self.clear_ignores || !self.ignores.is_empty() {
+            let mut ignores = if self.clear_ignores {
+                Vec::new()
+            } else {
+                watch_dir.ignores().to_vec()
+            };
+            ignores.extend(self.ignores);
+            watch_dir
+                .set_ignores(ignores)
+                .map_err(|err| err.to_string())?;
+        }
+        if let Some(retention) = self.retention {
+            let retention = if retention == "forever" {
+                None
+            } else {
+                Some(parse_duration(&retention).map_err(|err| err.to_string())?)
+            };
+            watch_dir.set_retention(retention);
+        }
+        if let Some(include_git_metadata) = self.include_git_metadata {
+            if include_git_metadata && watch_dir.is_file() {
+                return Err("--include-git-metadata only applies to directories".into());
+            }
+            watch_dir.set_include_git_metadata(include_git_metadata);
+        }
+        Ok(())
     }
 }
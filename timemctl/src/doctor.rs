@@ -0,0 +1,223 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use timem::{Config, InstanceLock, TimemHome, TomlConfig, WatchDir};
+
+/// Checks the config, every watched directory and the daemon, printing a suggested fix for each
+/// problem found. Returns the number of problems
+pub fn run(home: &TimemHome) -> usize {
+    let mut report = Report::default();
+
+    // Read-only, so diagnosing doesn't migrate or otherwise rewrite config.json
+    let config = match Config::load_read_only(home) {
+        Ok(config) => {
+            match config.load_error() {
+                Some(err_str) => report.fail(
+                    format!(
+                        "{} can't be loaded: {err_str}",
+                        home.config_path().display()
+                    ),
+                    "fix the file by hand, timemctl refuses to change the config until it loads",
+                ),
+                None => report.ok(format!("{} parses", home.config_path().display())),
+            }
+            Some(config)
+        }
+        Err(err_str) => {
+            report.fail(
+                format!("{} can't be loaded: {err_str}", home.config_path().display()),
+                "fix the file by hand, or move it away and re-add directories with `timemctl watch`",
+            );
+            None
+        }
+    };
+
+    let toml_path = home.toml_path();
+    if toml_path.exists() {
+        match TomlConfig::load(&toml_path) {
+            Ok(_) => report.ok(format!("{} parses", toml_path.display())),
+            Err(err_str) => report.fail(
+                format!("{} can't be parsed: {err_str}", toml_path.display()),
+                "fix the syntax error, timemserv ignores the file until then",
+            ),
+        }
+    }
+
+    if let Some(ref config) = config {
+        for watch_dir in config.iter_watched_dirs() {
+            check_watch_dir(&mut report, config, watch_dir);
+        }
+        for (path, reason) in config.iter_unavailable_dirs() {
+            report.fail(
+                format!("{} is unavailable: {reason}", path.display()),
+                "restore the directory or its snapshot repo, or re-add it with `timemctl watch`",
+            );
+        }
+        check_inotify_limit(&mut report, config);
+    }
+
+    match InstanceLock::running_pid(home) {
+        Ok(Some(pid)) => report.ok(format!("timemserv is running (pid {pid})")),
+        Ok(None) => report.fail(
+            "timemserv is not running, no snapshots are being taken",
+            "start it with `timemctl service install`, or run `timemserv` directly",
+        ),
+        Err(err_str) => report.fail(
+            format!("Could not determine whether timemserv is running: {err_str}"),
+            format!("check the permissions of {}", home.pid_path().display()),
+        ),
+    }
+
+    println!(
+        "\n{} problems, {} warnings",
+        report.problems, report.warnings
+    );
+    report.problems
+}
+
+fn check_watch_dir(report: &mut Report, config: &Config, watch_dir: &WatchDir) {
+    for root in watch_dir.roots() {
+        check_readable(report, &root, watch_dir.is_file());
+    }
+
+    let target_dir = match watch_dir.group() {
+        Some(group) => format!("group {group}"),
+        None => watch_dir.target_dir().display().to_string(),
+    };
+
+    match watch_dir.verify_repo() {
+        Ok(objects) => report.ok(format!(
+            "{target_dir} snapshot repo is intact ({objects} objects)"
+        )),
+        Err(err) => report.fail(
+            format!("{target_dir} snapshot repo is damaged: {err:#}"),
+            format!(
+                "run `git --git-dir {} fsck` to locate the damage",
+                watch_dir.get_repo().path().display()
+            ),
+        ),
+    }
+
+    let has_identity = watch_dir.identity().is_some() || config.settings().identity.is_some();
+    if has_identity || watch_dir.get_repo().signature().is_ok() {
+        report.ok(format!("{target_dir} has a git signature"));
+    } else {
+        report.warn(
+            format!(
+                "{target_dir} has no git signature, snapshots are authored by timem@<hostname>"
+            ),
+            "set `git config --global user.name/user.email`, or `identity` in config.toml",
+        );
+    }
+    if let Err(err) = watch_dir.signature() {
+        report.fail(
+            format!("{target_dir} snapshot signature is invalid: {err:#}"),
+            "fix the configured name and email",
+        );
+    }
+}
+
+fn check_readable(report: &mut Report, path: &Path, is_file: bool) {
+    let readable = if is_file {
+        fs::File::open(path).map(|_| ())
+    } else {
+        fs::read_dir(path).map(|_| ())
+    };
+    let path = path.display();
+    match readable {
+        Ok(_) => report.ok(format!("{path} is readable")),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => report.fail(
+            format!("{path} does not exist"),
+            "restore it, or restore a snapshot of it with `timemctl restore`",
+        ),
+        Err(err) => report.fail(
+            format!("{path} can't be read: {err}"),
+            format!("give your user read access, e.g. `chmod u+rx {path}`"),
+        ),
+    }
+}
+
+/// Recursive inotify watches take one watch per directory, so compare the number of watched
+/// directories against the per-user limit
+#[cfg(target_os = "linux")]
+fn check_inotify_limit(report: &mut Report, config: &Config) {
+    const MAX_USER_WATCHES: &str = "/proc/sys/fs/inotify/max_user_watches";
+
+    let limit: usize = match fs::read_to_string(MAX_USER_WATCHES)
+        .ok()
+        .and_then(|content| content.trim().parse().ok())
+    {
+        Some(limit) => limit,
+        None => {
+            report.warn(
+                format!("Could not read {MAX_USER_WATCHES}"),
+                "make sure /proc is mounted",
+            );
+            return;
+        }
+    };
+
+    let needed: usize = config
+        .iter_watched_dirs()
+        .flat_map(|watch_dir| watch_dir.roots())
+        .map(|root| count_dirs(&root))
+        .sum();
+    if needed > limit {
+        report.fail(
+            format!("Watched directories need {needed} inotify watches, but the limit is {limit}"),
+            format!(
+                "raise it with `sudo sysctl fs.inotify.max_user_watches={}` and persist it in /etc/sysctl.d/",
+                needed.next_power_of_two().max(2 * limit)
+            ),
+        );
+    } else if needed > limit / 2 {
+        report.warn(
+            format!("Watched directories use {needed} of {limit} inotify watches, which are shared with other programs"),
+            "consider raising fs.inotify.max_user_watches with sysctl",
+        );
+    } else {
+        report.ok(format!(
+            "Watched directories need {needed} of {limit} inotify watches"
+        ));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_inotify_limit(_report: &mut Report, _config: &Config) {}
+
+/// Counts `dir` and its subdirectories, without following symlinks
+#[cfg(target_os = "linux")]
+fn count_dirs(dir: &Path) -> usize {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 1;
+    };
+    1 + entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|ty| ty.is_dir()).unwrap_or(false))
+        .map(|entry| count_dirs(&entry.path()))
+        .sum::<usize>()
+}
+
+#[derive(Default)]
+struct Report {
+    problems: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&self, what: impl Display) {
+        println!("[ OK ] {what}");
+    }
+
+    fn warn(&mut self, what: impl Display, fix: impl Display) {
+        self.warnings += 1;
+        println!("[WARN] {what}\n       fix: {fix}");
+    }
+
+    fn fail(&mut self, what: impl Display, fix: impl Display) {
+        self.problems += 1;
+        println!("[FAIL] {what}\n       fix: {fix}");
+    }
+}
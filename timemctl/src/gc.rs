@@ -0,0 +1,178 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Error;
+
+use humantime::format_rfc3339_seconds;
+
+use timem::{Config, OrphanRepo, WatchDir};
+
+use crate::cli_args::CLIGcOrphans;
+use crate::confirm;
+
+/// Lists the snapshot repos no config entry points to, then deletes, archives or re-adopts the
+/// selected ones
+pub fn run(config: &mut Config, args: CLIGcOrphans) -> Result<(), Error> {
+    let orphans = OrphanRepo::find(config).map_err(Error::msg)?;
+    let selected: Vec<&OrphanRepo> = orphans
+        .iter()
+        .filter(|orphan| args.repos.is_empty() || args.repos.iter().any(|arg| matches(orphan, arg)))
+        .collect();
+    if selected.is_empty() {
+        println!("No orphaned snapshot repos");
+        return Ok(());
+    }
+
+    for orphan in selected.iter() {
+        print_orphan(orphan);
+    }
+
+    if args.delete {
+        if !args.yes && !confirm(&format!("Delete {} repos?", selected.len()))? {
+            return Ok(());
+        }
+        for orphan in selected {
+            fs::remove_dir_all(orphan.dotgit_dir())?;
+            println!("Deleted {}", orphan.dotgit_dir().display());
+        }
+    } else if let Some(ref archive_dir) = args.archive {
+        fs::create_dir_all(archive_dir)?;
+        for orphan in selected {
+            archive(orphan, archive_dir)?;
+        }
+    } else if args.adopt {
+        if args.to.is_some() && selected.len() != 1 {
+            return Err(Error::msg("--to needs exactly one repo to adopt"));
+        }
+        let (frequency, max_file_size) = args.settings(config.settings()).map_err(Error::msg)?;
+
+        let mut adopted = Vec::new();
+        for orphan in selected {
+            let target_dir = match (&args.to, orphan.target_dir()) {
+                (Some(to), _) => to.canonicalize()?,
+                (None, Some(target_dir)) => target_dir.to_owned(),
+                (None, None) => {
+                    eprintln!(
+                        "Skipping {}: could not determine the watched directory, pass --to",
+                        orphan.dotgit_dir().display()
+                    );
+                    continue;
+                }
+            };
+            if !target_dir.exists() {
+                eprintln!(
+                    "Skipping {}: {} does not exist, pass --to if it was moved",
+                    orphan.dotgit_dir().display(),
+                    target_dir.display()
+                );
+                continue;
+            }
+            if config.get_watched_dir(&target_dir).is_some() {
+                eprintln!(
+                    "Skipping {}: {} is already watched",
+                    orphan.dotgit_dir().display(),
+                    target_dir.display()
+                );
+                continue;
+            }
+            adopted.push(WatchDir::open(
+                orphan.dotgit_dir().to_owned(),
+                target_dir,
+                frequency,
+                max_file_size,
+            )?);
+        }
+
+        let count = adopted.len();
+        config
+            .modify(|config| {
+                adopted
+                    .into_iter()
+                    .for_each(|watch_dir| config.add_watched_dir(watch_dir));
+                Ok(())
+            })
+            .map_err(Error::msg)?;
+        println!("Adopted {count} repos");
+    }
+
+    Ok(())
+}
+
+/// Whether `arg` names the repo, by path or name, or its original directory
+fn matches(orphan: &OrphanRepo, arg: &str) -> bool {
+    let path = Path::new(arg);
+    orphan.dotgit_dir() == path
+        || orphan.dotgit_dir().file_name() == Some(path.as_os_str())
+        || orphan.target_dir() == Some(path)
+}
+
+fn print_orphan(orphan: &OrphanRepo) {
+    let original = orphan
+        .target_dir()
+        .map(|target_dir| target_dir.display().to_string())
+        .unwrap_or_else(|| "<unknown directory>".into());
+    let last_snapshot = orphan
+        .last_snapshot_time()
+        .map(|time| format_rfc3339_seconds(time).to_string())
+        .unwrap_or_else(|| "never".into());
+    println!(
+        "{original}\n    repo {}, {}, last snapshot {last_snapshot}",
+        orphan.dotgit_dir().display(),
+        format_size(orphan.disk_usage())
+    );
+}
+
+/// Writes the repo's history to `<archive_dir>/<repo name>.bundle`, then deletes the repo
+fn archive(orphan: &OrphanRepo, archive_dir: &Path) -> Result<(), Error> {
+    if orphan.last_snapshot_time().is_none() {
+        eprintln!(
+            "Skipping {}: it has no snapshots to archive",
+            orphan.dotgit_dir().display()
+        );
+        return Ok(());
+    }
+
+    let name = orphan
+        .dotgit_dir()
+        .file_name()
+        .ok_or(Error::msg("Snapshot repo has no name"))?;
+    let bundle_path = archive_dir.join(format!("{}.bundle", name.to_string_lossy()));
+
+    let status = Command::new("git")
+        .arg("--git-dir")
+        .arg(orphan.dotgit_dir())
+        .args(["bundle", "create"])
+        .arg(&bundle_path)
+        .arg("--all")
+        .status()
+        .map_err(|err| Error::msg(format!("Failed to run git: {err}")))?;
+    if !status.success() {
+        return Err(Error::msg(format!(
+            "git bundle create failed for {} ({status})",
+            orphan.dotgit_dir().display()
+        )));
+    }
+
+    fs::remove_dir_all(orphan.dotgit_dir())?;
+    println!(
+        "Archived {} to {}",
+        orphan.dotgit_dir().display(),
+        bundle_path.display()
+    );
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
@@ -1,8 +1,17 @@
 mod cli_args;
-use cli_args::{Args, Command as ArgCommand};
+mod doctor;
+mod gc;
+mod service;
+use cli_args::{Args, CLIConfig, CLIService, Command as ArgCommand};
 use std::fs;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use timem::{exit_error, logger_init, Config, WatchDir, CONFIG_DIR};
+use std::time::{Duration, SystemTime};
+use timem::{
+    exit_error, logger_init, Config, GlobalSettings, GroupRoot, HealthState, InstanceLock,
+    NestedRoots, OrphanRepo, Pause, SnapshotIdentity, TimemHome, TomlConfig, WatchDir,
+    WatchPattern,
+};
 
 use structopt::StructOpt;
 
@@ -10,12 +19,25 @@ use git2::{self, DiffFormat, Oid};
 
 use chrono::{DateTime, Local, TimeZone, Utc};
 
+use humantime::{format_duration, format_rfc3339_seconds};
+
 use anyhow::Error;
 
 fn main() -> Result<(), Error> {
     logger_init();
     let args = Args::from_args();
-    let mut config = match Config::new(false) {
+    let home = match TimemHome::resolve(args.config_root) {
+        Ok(home) => home,
+        Err(err_str) => {
+            exit_error!("Config error: {err_str}");
+        }
+    };
+    // The doctor reports a broken config instead of exiting on it
+    if let ArgCommand::Doctor = args.cmd {
+        let problems = doctor::run(&home);
+        std::process::exit(if problems > 0 { 1 } else { 0 });
+    }
+    let mut config = match Config::new(&home, false) {
         Ok(config) => config,
         Err(err_str) => {
             exit_error!("Config error: {err_str}");
@@ -24,25 +46,141 @@ fn main() -> Result<(), Error> {
 
     match args.cmd {
         ArgCommand::Watch(cli_add) => {
-            let watch_dir: WatchDir = match cli_add.into() {
+            let watched = config.get_watched_dir(config.resolve_target(&cli_add.dir));
+            if let Some(group) = watched.and_then(WatchDir::group) {
+                let dir = &cli_add.dir;
+                exit_error!("Input error: {dir} is part of the group {group}");
+            }
+            let watch_dir: WatchDir = match cli_add.into_watch_dir(&home) {
                 Ok(wdir) => wdir,
                 Err(err_str) => {
                     exit_error!("Input error: {err_str}");
                 }
             };
+            let path = watch_dir.target_dir().to_owned();
 
-            config.add_watched_dir(watch_dir);
-            match config.flush_config() {
+            match config.modify(|config| {
+                config.add_watched_dir(watch_dir);
+                Ok(())
+            }) {
                 Ok(_) => {}
                 Err(err_str) => {
                     exit_error!("Config flush error: {err_str}");
                 }
             }
+            warn_overlaps(&config, &path);
+        }
+        ArgCommand::WatchGroup(cli_group) => {
+            let name = cli_group.name.clone();
+            let watch_dir = match cli_group.into_watch_dir(&home) {
+                Ok(watch_dir) => watch_dir,
+                Err(err_str) => {
+                    exit_error!("Input error: {err_str}");
+                }
+            };
+            for root in watch_dir.roots() {
+                let watched = config.get_watched_dir(config.resolve_target(&root));
+                if let Some(watched) = watched.filter(|watched| watched.group() != Some(&name)) {
+                    let root = root.display();
+                    exit_error!("Input error: {root} is already watched as {watched}");
+                }
+            }
+
+            let key = watch_dir.target_dir().to_owned();
+            let updated = config
+                .modify(|config| {
+                    // A group is keyed by its first root, which changes if the roots are reordered
+                    let previous: Vec<PathBuf> = config
+                        .iter_watched_dirs()
+                        .filter(|watched| watched.group() == Some(&name))
+                        .map(|watched| watched.target_dir().to_owned())
+                        .collect();
+                    for path in previous {
+                        config.remove_watched_dir(path);
+                    }
+                    let updated = watch_dir.to_string();
+                    config.add_watched_dir(watch_dir);
+                    Ok(updated)
+                })
+                .map_err(Error::msg)?;
+            println!("{updated}");
+            warn_overlaps(&config, &key);
+        }
+        ArgCommand::WatchPattern(cli_pattern) => {
+            let pattern: WatchPattern = match cli_pattern.to_watch_pattern() {
+                Ok(pattern) => pattern,
+                Err(err_str) => {
+                    exit_error!("Input error: {err_str}");
+                }
+            };
+            println!("{pattern}");
+            let added = config
+                .modify(|config| Ok(config.add_watch_pattern(pattern)))
+                .map_err(Error::msg)?;
+            for path in added.iter() {
+                println!("Watching {}", path.display());
+            }
+        }
+        ArgCommand::Set(set) => {
+            let dir = config.resolve_target(&set.dir);
+            let updated = config
+                .modify(|config| {
+                    let watch_dir = config
+                        .get_watched_dir_mut(&dir)
+                        .ok_or(format!("Directory {:?} is not being watched", dir))?;
+                    set.apply_to(watch_dir)?;
+                    Ok(watch_dir.to_string())
+                })
+                .map_err(Error::msg)?;
+            println!("{updated}");
+        }
+        ArgCommand::Pause(pause) => {
+            let new_pause = match pause.to_pause() {
+                Ok(new_pause) => new_pause,
+                Err(err_str) => {
+                    exit_error!("Input error: {err_str}");
+                }
+            };
+            let dir = pause.dir.as_deref().map(|dir| config.resolve_target(dir));
+            config
+                .modify(|config| {
+                    match dir {
+                        Some(ref dir) => config
+                            .get_watched_dir_mut(dir)
+                            .ok_or(format!("Directory {:?} is not being watched", dir))?
+                            .set_pause(Some(new_pause)),
+                        None => config.set_global_pause(Some(new_pause)),
+                    }
+                    Ok(())
+                })
+                .map_err(Error::msg)?;
+            match dir {
+                Some(dir) => println!("{}: {new_pause}", dir.display()),
+                None => println!("All directories {new_pause}"),
+            }
+        }
+        ArgCommand::Resume(resume) => {
+            let dir = resume.dir.as_deref().map(|dir| config.resolve_target(dir));
+            config
+                .modify(|config| {
+                    match dir {
+                        Some(ref dir) => config
+                            .get_watched_dir_mut(dir)
+                            .ok_or(format!("Directory {:?} is not being watched", dir))?
+                            .set_pause(None),
+                        None => {
+                            config.set_global_pause(None);
+                            config
+                                .iter_watched_dirs_mut()
+                                .for_each(|watch_dir| watch_dir.set_pause(None));
+                        }
+                    }
+                    Ok(())
+                })
+                .map_err(Error::msg)?;
         }
         ArgCommand::Log(log) => {
-            let dir = Path::new(&log.dir)
-                .canonicalize()
-                .unwrap_or_else(|_| PathBuf::from(&log.dir));
+            let dir = config.resolve_target(&log.dir);
             let watch_dir = config.get_watched_dir(&dir).ok_or(Error::msg(format!(
                 "Directory {:?} is not being watched",
                 dir
@@ -70,28 +208,248 @@ fn main() -> Result<(), Error> {
                     println!("{}", commit_str);
                 });
         }
-        ArgCommand::ClearConf => {
-            if let Some(ref config_dir) = *CONFIG_DIR {
-                let config_file = config_dir.join("config.json");
-                match fs::remove_file(config_file) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        exit_error!("Failed to remove config file: {e}");
-                    }
+        ArgCommand::Recover(recover) => {
+            let orphans = OrphanRepo::find(&config).map_err(Error::msg)?;
+            if orphans.is_empty() {
+                println!(
+                    "Every snapshot repo in {} is already in the config",
+                    home.dotgit_dir_dir().display()
+                );
+                return Ok(());
+            }
+            let (frequency, max_file_size) =
+                recover.settings(config.settings()).map_err(Error::msg)?;
+
+            let mut recovered = Vec::new();
+            for orphan in orphans.iter() {
+                let Some(target_dir) = orphan.target_dir() else {
+                    eprintln!(
+                        "Skipping {}: could not determine the watched directory",
+                        orphan.dotgit_dir().display()
+                    );
+                    continue;
+                };
+                if !target_dir.exists() {
+                    eprintln!(
+                        "Skipping {}: {} no longer exists",
+                        orphan.dotgit_dir().display(),
+                        target_dir.display()
+                    );
+                    continue;
+                }
+                if config.get_watched_dir(target_dir).is_some() {
+                    eprintln!(
+                        "Skipping {}: {} is already watched using another repo",
+                        orphan.dotgit_dir().display(),
+                        target_dir.display()
+                    );
+                    continue;
+                }
+
+                let last_snapshot = orphan
+                    .last_snapshot_time()
+                    .map(|time| format_rfc3339_seconds(time).to_string())
+                    .unwrap_or_else(|| "never".into());
+                if !recover.all
+                    && !confirm(&format!(
+                        "Recover {} (last snapshot {last_snapshot})?",
+                        target_dir.display()
+                    ))?
+                {
+                    continue;
                 }
+                recovered.push(WatchDir::open(
+                    orphan.dotgit_dir().to_owned(),
+                    target_dir.to_owned(),
+                    frequency,
+                    max_file_size,
+                )?);
+            }
+
+            let count = recovered.len();
+            config
+                .modify(|config| {
+                    recovered
+                        .into_iter()
+                        .for_each(|watch_dir| config.add_watched_dir(watch_dir));
+                    Ok(())
+                })
+                .map_err(Error::msg)?;
+            println!("Recovered {count} directories");
+        }
+        ArgCommand::GcOrphans(gc_orphans) => {
+            gc::run(&mut config, gc_orphans)?;
+        }
+        ArgCommand::ClearConf(clear_conf) => {
+            let question = if clear_conf.purge_repos {
+                "Unwatch all directories and delete ALL their snapshots? This can't be undone"
             } else {
-                exit_error!("Failed to find config directory");
+                "Unwatch all directories? The config is backed up first"
+            };
+            if !clear_conf.yes && !confirm(question)? {
+                // Non-zero, so scripts without a terminal can tell nothing was cleared
+                eprintln!("Aborted");
+                std::process::exit(1);
+            }
+
+            let dotgit_dirs: Vec<PathBuf> = config.iter_dotgit_dirs().map(PathBuf::from).collect();
+            let backup = config
+                .modify(|config| {
+                    let backup = config.backup()?;
+                    config.clear();
+                    Ok(backup)
+                })
+                .map_err(Error::msg)?;
+            if let Some(backup) = backup {
+                println!("Config backed up to {}", backup.display());
+            }
+
+            if clear_conf.purge_repos {
+                for dotgit_dir in dotgit_dirs.iter().filter(|dir| dir.exists()) {
+                    fs::remove_dir_all(dotgit_dir)?;
+                    println!("Deleted {}", dotgit_dir.display());
+                }
+            }
+        }
+        ArgCommand::Config(CLIConfig::RestoreBackup { backup, list }) => {
+            let backups = Config::list_backups(&home).map_err(Error::msg)?;
+            if list {
+                backups
+                    .iter()
+                    .for_each(|backup| println!("{}", backup.display()));
+                return Ok(());
+            }
+
+            let backup_path = match backup {
+                Some(backup) if Path::new(&backup).exists() => PathBuf::from(backup),
+                Some(backup) => home.backups_dir().join(backup),
+                None => backups
+                    .last()
+                    .cloned()
+                    .ok_or(Error::msg("There are no config backups"))?,
+            };
+            let previous = config
+                .modify(|config| {
+                    let previous = config.backup()?;
+                    config.restore_backup(&backup_path)?;
+                    Ok(previous)
+                })
+                .map_err(Error::msg)?;
+            println!("Restored {}", backup_path.display());
+            if let Some(previous) = previous {
+                println!("Previous config backed up to {}", previous.display());
+            }
+        }
+        ArgCommand::Config(CLIConfig::Export { output }) => {
+            let toml = config.export_toml().to_toml_string().map_err(Error::msg)?;
+            match output {
+                Some(output) => fs::write(output, toml)?,
+                None => print!("{toml}"),
+            }
+        }
+        ArgCommand::Config(CLIConfig::Import { file }) => {
+            let file = match file {
+                Some(file) => PathBuf::from(file),
+                None => home.toml_path(),
+            };
+            let toml = TomlConfig::load(&file).map_err(Error::msg)?;
+            let problems = config
+                .modify(|config| config.import_toml(&toml))
+                .map_err(Error::msg)?;
+            for problem in problems.iter() {
+                eprintln!("Skipped {problem}");
+            }
+            if !problems.is_empty() {
+                return Err(Error::msg(format!(
+                    "{} directories could not be imported",
+                    problems.len()
+                )));
             }
         }
+        ArgCommand::Service(service) => match service {
+            CLIService::Install { timemserv_path } => {
+                service::install(&home, timemserv_path.as_deref())?;
+            }
+            CLIService::Uninstall => {
+                service::uninstall()?;
+            }
+        },
         ArgCommand::List => {
             config
                 .iter_watched_dirs()
                 .for_each(|watch_dir| println!("{}", watch_dir));
+            config
+                .iter_watch_patterns()
+                .for_each(|pattern| println!("pattern {pattern}"));
+            config
+                .iter_unavailable_dirs()
+                .for_each(|(path, reason)| println!("{} (unavailable: {reason})", path.display()));
         }
+        ArgCommand::Status => {
+            match InstanceLock::running_pid(&home) {
+                Ok(Some(pid)) => println!("timemserv is running (pid {pid})"),
+                Ok(None) => println!("timemserv is not running"),
+                Err(err_str) => {
+                    println!("Could not determine whether timemserv is running: {err_str}")
+                }
+            }
+
+            let health = match HealthState::load(&home) {
+                Ok(health) => health,
+                Err(err_str) => {
+                    exit_error!("Health state error: {err_str}");
+                }
+            };
+
+            for watch_dir in config.iter_watched_dirs() {
+                let last_snapshot = watch_dir
+                    .get_head_commit()
+                    .ok()
+                    .and_then(|commit| format_git2_time(&commit.time()).ok())
+                    .unwrap_or_else(|| "never".into());
+                match watch_dir.group() {
+                    Some(group) => println!("group {group}: last snapshot {last_snapshot}"),
+                    None => println!(
+                        "{}: last snapshot {last_snapshot}",
+                        watch_dir.target_dir().display()
+                    ),
+                }
+                if let Some(pause) = watch_dir.active_pause() {
+                    println!("    {}", pause.to_string().to_uppercase());
+                }
+
+                let Some(dir_health) = health.get(watch_dir.target_dir()) else {
+                    continue;
+                };
+                if dir_health.is_healthy() {
+                    continue;
+                }
+                let retry_in = dir_health
+                    .retry_after
+                    .and_then(|retry_after| retry_after.duration_since(SystemTime::now()).ok())
+                    .map(|delay| format_duration(Duration::from_secs(delay.as_secs())).to_string())
+                    .unwrap_or_else(|| "now".into());
+                println!(
+                    "    {} after {} consecutive failures, retrying in {retry_in}",
+                    if dir_health.quarantined {
+                        "QUARANTINED"
+                    } else {
+                        "FAILING"
+                    },
+                    dir_health.consecutive_failures
+                );
+                if let Some(ref last_error) = dir_health.last_error {
+                    println!("    last error: {last_error}");
+                }
+            }
+
+            for (path, reason) in config.iter_unavailable_dirs() {
+                println!("{}: UNAVAILABLE, {reason}", path.display());
+            }
+        }
+        ArgCommand::Doctor => unreachable!(),
         ArgCommand::Diff(diff) => {
-            let dir = Path::new(&diff.dir)
-                .canonicalize()
-                .unwrap_or_else(|_| PathBuf::from(&diff.dir));
+            let dir = config.resolve_target(&diff.dir);
             let watch_dir = config.get_watched_dir(dir).ok_or(Error::msg(format!(
                 "Directory {:?} is not being watched",
                 diff.dir
@@ -118,9 +476,7 @@ fn main() -> Result<(), Error> {
             })?;
         }
         ArgCommand::Restore(restore) => {
-            let dir = Path::new(&restore.dir)
-                .canonicalize()
-                .unwrap_or_else(|_| PathBuf::from(&restore.dir));
+            let dir = config.resolve_target(&restore.dir);
             let mut watch_dir = config.get_watched_dir(&dir).ok_or(Error::msg(format!(
                 "The directory {:?} is not watched",
                 &dir
@@ -134,6 +490,43 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// Warns about watched entries nested in the one keyed by `path`, or containing it, and what the
+/// `nested_roots` policy does with them
+fn warn_overlaps(config: &Config, path: &Path) {
+    let Some(watch_dir) = config.get_watched_dir(path) else {
+        return;
+    };
+    for (other, nested) in config.overlapping(path) {
+        let (inner, outer) = match nested {
+            true => (other, watch_dir),
+            false => (watch_dir, other),
+        };
+        let consequence = match config.nested_roots() {
+            NestedRoots::Exclude => format!(
+                "snapshots of {} leave it out (nested_roots = \"exclude\")",
+                outer.describe()
+            ),
+            NestedRoots::Include => {
+                "its files are snapshotted by both (nested_roots = \"include\")".to_owned()
+            }
+        };
+        eprintln!(
+            "Warning: {} is nested in {}, {consequence}",
+            inner.describe(),
+            outer.describe()
+        );
+    }
+}
+
+/// Asks a yes/no question on the terminal, defaulting to no
+fn confirm(question: &str) -> Result<bool, Error> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
 fn format_git2_time(time: &git2::Time) -> Result<String, Error> {
     // Convert the timestamp to NaiveDateTime
     let naive = DateTime::from_timestamp(time.seconds(), 0)
@@ -0,0 +1,122 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use directories::BaseDirs;
+
+use anyhow::Error;
+
+use timem::TimemHome;
+
+const UNIT_NAME: &str = "timemserv.service";
+
+/// Writes the timemserv systemd user unit, then enables and starts it
+pub fn install(home: &TimemHome, timemserv_path: Option<&str>) -> Result<(), Error> {
+    let timemserv_path = match timemserv_path {
+        Some(path) => Path::new(path).canonicalize()?,
+        None => find_timemserv().ok_or(Error::msg(
+            "Could not find the timemserv binary, pass its location with --timemserv-path",
+        ))?,
+    };
+
+    let unit_path = unit_path()?;
+    fs::create_dir_all(
+        unit_path
+            .parent()
+            .ok_or(Error::msg("Could not get parent directory of unit file"))?,
+    )?;
+    fs::write(&unit_path, unit_file(&timemserv_path, home))?;
+    println!("Wrote {}", unit_path.display());
+
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", "--now", UNIT_NAME])?;
+    println!("Enabled and started {UNIT_NAME}");
+    Ok(())
+}
+
+/// Stops and disables the timemserv systemd user unit, then removes it
+pub fn uninstall() -> Result<(), Error> {
+    let unit_path = unit_path()?;
+    if !unit_path.exists() {
+        return Err(Error::msg(format!(
+            "{} does not exist, nothing to uninstall",
+            unit_path.display()
+        )));
+    }
+
+    systemctl(&["disable", "--now", UNIT_NAME])?;
+    fs::remove_file(&unit_path)?;
+    systemctl(&["daemon-reload"])?;
+    println!("Removed {}", unit_path.display());
+    Ok(())
+}
+
+fn unit_file(timemserv_path: &Path, home: &TimemHome) -> String {
+    format!(
+        "[Unit]
+Description=TimeM directory snapshot service
+
+[Service]
+Type=notify
+ExecStart={} --config {}
+Environment=LOG_CONFIG=info
+Restart=on-failure
+WatchdogSec=120
+TimeoutStopSec=30
+
+[Install]
+WantedBy=default.target
+",
+        quote_unit_arg(timemserv_path),
+        quote_unit_arg(home.root())
+    )
+}
+
+/// Quotes a path for a unit file command line, so paths with spaces stay one argument. `%` starts
+/// a systemd specifier and is doubled
+fn quote_unit_arg(path: &Path) -> String {
+    let escaped = path
+        .display()
+        .to_string()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    format!("\"{escaped}\"")
+}
+
+fn unit_path() -> Result<PathBuf, Error> {
+    let base_dirs = BaseDirs::new().ok_or(Error::msg("Could not locate OS config directory"))?;
+    Ok(base_dirs.config_dir().join("systemd/user").join(UNIT_NAME))
+}
+
+/// Looks for timemserv next to the running timemctl, then on `$PATH`
+fn find_timemserv() -> Option<PathBuf> {
+    let sibling = env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name("timemserv"));
+    let on_path = env::var_os("PATH")
+        .into_iter()
+        .flat_map(|paths| env::split_paths(&paths).collect::<Vec<_>>())
+        .map(|dir| dir.join("timemserv"));
+
+    sibling
+        .into_iter()
+        .chain(on_path)
+        .find(|path| path.is_file())
+        .and_then(|path| path.canonicalize().ok())
+}
+
+fn systemctl(args: &[&str]) -> Result<(), Error> {
+    let status = Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .status()?;
+    if !status.success() {
+        return Err(Error::msg(format!(
+            "`systemctl --user {}` failed with {status}",
+            args.join(" ")
+        )));
+    }
+    Ok(())
+}
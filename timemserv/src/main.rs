@@ -1,50 +1,317 @@
+mod sd_notify;
+
 use anyhow::Error;
+use std::collections::HashSet;
+use std::io;
 use std::path::PathBuf;
 use std::result;
-use timem::{exit_error, log, logger_init, Config};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use signal_hook::{consts::TERM_SIGNALS, flag as signal_flag};
+use timem::{
+    exit_error, log, logger_init, record_progress, Config, HealthState, InstanceLock,
+    SnapshotOutcome, TimemHome,
+};
+
+use structopt::StructOpt;
+
+use sd_notify::SdNotifier;
 
 type Result<T> = result::Result<T, Error>;
 
+#[derive(Debug, StructOpt)]
+#[structopt(name = "timemserv", about = "TimeM snapshot service")]
+struct Args {
+    #[structopt(long = "config", parse(from_os_str))]
+    /// TimeM home directory to use instead of $TIMEM_HOME or the OS config directory
+    config_root: Option<PathBuf>,
+}
+
+/// How far the wall clock may run ahead of the monotonic clock before we assume the system was
+/// suspended
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(5);
+/// How long final snapshots may take on shutdown before the remaining dirs are given up on
+const SHUTDOWN_BUDGET: Duration = Duration::from_secs(20);
+/// How often snapshots past their retention are pruned. Pruning repacks the whole snapshot repo,
+/// so it isn't done after every snapshot
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Delay before the first pruning, so it doesn't add to the startup scan
+const FIRST_PRUNE_DELAY: Duration = Duration::from_secs(10 * 60);
+
 fn main() -> Result<()> {
     logger_init();
+    let args = Args::from_args();
+    let home = match TimemHome::resolve(args.config_root) {
+        Ok(home) => home,
+        Err(err_str) => {
+            exit_error!("Config error: {err_str}");
+        }
+    };
+
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    for signal in TERM_SIGNALS {
+        // A second signal while we are flushing terminates immediately
+        signal_flag::register_conditional_shutdown(*signal, 1, Arc::clone(&shutdown_requested))?;
+        signal_flag::register(*signal, Arc::clone(&shutdown_requested))?;
+    }
+
+    let instance_lock = match InstanceLock::acquire(&home) {
+        Ok(lock) => lock,
+        Err(err_str) => {
+            exit_error!("{err_str}");
+        }
+    };
+
     log::info!("TimeM Service Started");
-    let mut config = match Config::new(true) {
+    let mut config = match Config::new(&home, true) {
         Ok(config) => config,
         Err(err_str) => {
             exit_error!("Config error: {err_str}");
         }
     };
-    loop {
+    let mut health = match HealthState::load(&home) {
+        Ok(health) => health,
+        Err(err_str) => {
+            exit_error!("Health state error: {err_str}");
+        }
+    };
+
+    // Ready before the catch-up scan below, which stages every watched tree and can outlast the
+    // start timeout on large trees. Events arriving meanwhile are queued
+    let notifier = SdNotifier::from_env();
+    if let Some(ref notifier) = notifier {
+        log_notify_error(notifier.ready(&status_line(&config)));
+        log_notify_error(notifier.spawn_watchdog());
+    }
+
+    if let Err(err_str) = config.sync_toml() {
+        log::error!("Applying config.toml: {err_str}");
+    }
+    // Directories matching a watch pattern may have been created while we weren't running
+    if let Err(err_str) = config.watch_pattern_matches() {
+        log::error!("Watching new pattern matches: {err_str}");
+    }
+    config.mark_dirty_dirs();
+    health.retain_watched(|path| config.get_watched_dir(path).is_some());
+    if let Some(ref notifier) = notifier {
+        log_notify_error(notifier.status(&status_line(&config)));
+    }
+
+    let mut suspend_detector = SuspendDetector::new();
+    let mut next_prune = Instant::now() + FIRST_PRUNE_DELAY;
+    // Directories that had pending changes while paused, snapshotted as soon as they resume
+    let mut paused_dirs: HashSet<PathBuf> = HashSet::new();
+    while !shutdown_requested.load(Ordering::Relaxed) {
+        record_progress();
         match config.update_if_changed() {
             Ok(_) => {}
             Err(err_str) => log::error!("Updating config: {err_str}"),
         }
 
-        let mut snapshotted_paths = Vec::with_capacity(config.dirs_with_changes.len());
+        if suspend_detector.resumed() {
+            log::info!("Resumed from suspend, scanning watched directories for changes");
+            config.mark_dirty_dirs();
+        }
+
+        let mut finished_paths = Vec::with_capacity(config.dirs_with_changes.len());
+        let mut health_changed = false;
         for changed_path in config.dirs_with_changes.iter() {
-            if handle_dir(&config, changed_path.clone())? {
-                snapshotted_paths.push(changed_path.to_owned());
+            let watch_dir = match config.get_watched_dir(changed_path) {
+                Some(wd) => wd,
+                None => {
+                    log::warn!(
+                        "Directory marked as changed no longer in list of watched directories: {:?}",
+                        changed_path
+                    );
+                    finished_paths.push(changed_path.to_owned());
+                    continue;
+                }
+            };
+
+            // Paused directories stay marked as changed until they are resumed
+            if watch_dir.active_pause().is_some() {
+                paused_dirs.insert(changed_path.to_owned());
+                continue;
+            }
+
+            if !health.should_attempt(changed_path) {
+                continue;
+            }
+
+            let catch_up = paused_dirs.remove(changed_path);
+            if catch_up {
+                log::info!("{:?} resumed, taking catch-up snapshot", changed_path);
+            }
+            match watch_dir.snapshot(catch_up) {
+                Ok(outcome) => {
+                    health_changed |= health.record_success(changed_path);
+                    // No changes happens when e.g. only ignored or oversized files changed
+                    if outcome != SnapshotOutcome::NotDue {
+                        finished_paths.push(changed_path.to_owned());
+                    }
+                }
+                Err(err) => {
+                    let dir_health = health.record_failure(changed_path, format!("{err:#}"));
+                    log::error!(
+                        "Failed to snapshot {:?} ({} consecutive failures): {err:#}",
+                        changed_path,
+                        dir_health.consecutive_failures
+                    );
+                    health_changed = true;
+                }
             }
         }
 
-        snapshotted_paths.iter().for_each(|path| {
+        finished_paths.iter().for_each(|path| {
             config.dirs_with_changes.remove(path);
         });
 
+        if Instant::now() >= next_prune {
+            prune_all(&mut config);
+            next_prune = Instant::now() + PRUNE_INTERVAL;
+        }
+
+        if health_changed {
+            if let Err(err_str) = health.flush() {
+                log::error!("Failed to write health state: {err_str}");
+            }
+        }
+
+        if let Some(ref notifier) = notifier {
+            if health_changed || !finished_paths.is_empty() {
+                log_notify_error(notifier.status(&status_line(&config)));
+            }
+        }
+
         std::hint::spin_loop();
     }
+
+    log::info!("Shutdown requested, flushing pending changes");
+    if let Some(ref notifier) = notifier {
+        log_notify_error(notifier.stopping());
+    }
+    // Pick up the events that arrived before the signal, then stop listening for more
+    if let Err(err_str) = config.update_if_changed() {
+        log::error!("Updating config: {err_str}");
+    }
+    config.stop_watching_changes();
+
+    let exit_code = flush_pending(&config, &mut health);
+    drop(config);
+    // `process::exit` skips destructors, so release the pidfile explicitly
+    drop(instance_lock);
+
+    log::info!("TimeM Service Stopped");
+    std::process::exit(exit_code);
 }
 
-fn handle_dir(config: &Config, path: PathBuf) -> Result<bool> {
-    let watch_dir = match config.get_watched_dir(&path) {
-        Some(wd) => wd,
-        None => {
-            panic!(
-                "Directory marked as changed no longer in list of watched directories: {:?}",
-                &path
+/// Takes final snapshots of every directory with pending changes, giving up on the remaining ones
+/// once `SHUTDOWN_BUDGET` is spent, and releases any index locks left behind. Returns the process
+/// exit code
+fn flush_pending(config: &Config, health: &mut HealthState) -> i32 {
+    let started = Instant::now();
+    let mut exit_code = 0;
+
+    for changed_path in config.iter_changed_paths() {
+        if started.elapsed() >= SHUTDOWN_BUDGET {
+            log::warn!(
+                "Shutdown time budget exhausted, not snapshotting {:?}",
+                changed_path
             );
+            exit_code = 1;
+            continue;
         }
-    };
 
-    watch_dir.snapshot(false)
+        let Some(watch_dir) = config.get_watched_dir(changed_path) else {
+            continue;
+        };
+        if watch_dir.active_pause().is_some() {
+            log::info!("{:?} is paused, not snapshotting", changed_path);
+            continue;
+        }
+        match watch_dir.snapshot(true) {
+            Ok(_) => {
+                health.record_success(changed_path);
+            }
+            Err(err) => {
+                log::error!("Failed final snapshot of {:?}: {err:#}", changed_path);
+                health.record_failure(changed_path, format!("{err:#}"));
+                exit_code = 1;
+            }
+        }
+    }
+
+    for watch_dir in config.iter_watched_dirs() {
+        match watch_dir.release_index_lock() {
+            Ok(true) => log::warn!("Released index lock of {:?}", watch_dir.target_dir()),
+            Ok(false) => {}
+            Err(err) => {
+                log::error!(
+                    "Failed to release index lock of {:?}: {err:#}",
+                    watch_dir.target_dir()
+                );
+                exit_code = 1;
+            }
+        }
+    }
+
+    if let Err(err_str) = health.flush() {
+        log::error!("Failed to write health state: {err_str}");
+    }
+
+    exit_code
+}
+
+/// Drops the snapshots past their retention period of every watched directory that isn't paused
+fn prune_all(config: &mut Config) {
+    for watch_dir in config.iter_watched_dirs_mut() {
+        if watch_dir.active_pause().is_some() {
+            continue;
+        }
+        if let Err(err) = watch_dir.prune_snapshots() {
+            log::error!(
+                "Failed to prune snapshots of {:?}: {err:#}",
+                watch_dir.target_dir()
+            );
+        }
+    }
+}
+
+fn status_line(config: &Config) -> String {
+    format!(
+        "Watching {} directories, {} with pending changes",
+        config.iter_watched_dirs().count(),
+        config.dirs_with_changes.len()
+    )
+}
+
+fn log_notify_error(result: io::Result<()>) {
+    if let Err(err) = result {
+        log::warn!("Failed to notify systemd: {err}");
+    }
+}
+
+/// Detects resumes from suspend. The monotonic clock stops while the system is suspended but the
+/// wall clock does not, so a growing gap between the two means we slept
+struct SuspendDetector {
+    instant: Instant,
+    wall_time: SystemTime,
+}
+
+impl SuspendDetector {
+    fn new() -> Self {
+        Self {
+            instant: Instant::now(),
+            wall_time: SystemTime::now(),
+        }
+    }
+
+    fn resumed(&mut self) -> bool {
+        let monotonic_elapsed = self.instant.elapsed();
+        let wall_elapsed = self.wall_time.elapsed().unwrap_or_default();
+        *self = Self::new();
+        wall_elapsed.saturating_sub(monotonic_elapsed) > SUSPEND_THRESHOLD
+    }
 }
@@ -0,0 +1,163 @@
+use std::env;
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::thread;
+use std::time::Duration;
+
+use timem::{log, progress_count};
+
+/// Minimal client for the systemd notify protocol (`sd_notify(3)`): newline separated
+/// `KEY=VALUE` assignments sent as a single datagram to the socket in `$NOTIFY_SOCKET`
+pub struct SdNotifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    watchdog_interval: Option<Duration>,
+}
+
+impl SdNotifier {
+    /// Connects to the socket systemd passed in `$NOTIFY_SOCKET`. Returns `None` when not started
+    /// by systemd as a `Type=notify` service
+    pub fn from_env() -> Option<Self> {
+        let socket_path = env::var_os("NOTIFY_SOCKET")?;
+        let socket_path = socket_path.to_str()?;
+
+        let addr = if let Some(abstract_name) = socket_path.strip_prefix('@') {
+            abstract_addr(abstract_name)
+        } else {
+            SocketAddr::from_pathname(socket_path)
+        };
+        let mut notifier = match addr.and_then(Self::with_addr) {
+            Ok(notifier) => notifier,
+            Err(err) => {
+                log::error!("Failed to connect to NOTIFY_SOCKET {socket_path:?}: {err}");
+                return None;
+            }
+        };
+
+        notifier.watchdog_interval = watchdog_interval_from_env();
+        Some(notifier)
+    }
+
+    #[cfg(test)]
+    pub fn connect<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        Self::with_addr(SocketAddr::from_pathname(path)?)
+    }
+
+    fn with_addr(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog_interval: None,
+        })
+    }
+
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+
+    pub fn ready(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("READY=1\nSTATUS={status}"))
+    }
+
+    pub fn status(&self, status: &str) -> io::Result<()> {
+        self.notify(&format!("STATUS={status}"))
+    }
+
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify("STOPPING=1\nSTATUS=Flushing pending snapshots")
+    }
+
+    /// Starts pinging the watchdog every half `WatchdogSec` from a background thread, as long as
+    /// `progress_count` moved since the previous ping. Long snapshots and scans record progress as
+    /// they go so they aren't mistaken for a hang, while a stuck daemon still gets restarted
+    pub fn spawn_watchdog(&self) -> io::Result<()> {
+        let Some(interval) = self.watchdog_interval else {
+            return Ok(());
+        };
+        let notifier = Self {
+            socket: self.socket.try_clone()?,
+            addr: self.addr.clone(),
+            watchdog_interval: None,
+        };
+        thread::Builder::new()
+            .name("watchdog".into())
+            .spawn(move || {
+                let mut last_progress = None;
+                loop {
+                    let progress = progress_count();
+                    if last_progress != Some(progress) {
+                        if let Err(err) = notifier.notify("WATCHDOG=1") {
+                            log::warn!("Failed to notify systemd: {err}");
+                        }
+                        last_progress = Some(progress);
+                    }
+                    thread::sleep(interval / 2);
+                }
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> io::Result<SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    SocketAddr::from_abstract_name(name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &str) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+/// Reads `$WATCHDOG_USEC`, ignoring it if `$WATCHDOG_PID` is meant for another process
+fn watchdog_interval_from_env() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stand_in_socket(name: &str) -> (UnixDatagram, std::path::PathBuf) {
+        let path = env::temp_dir().join(format!("timem-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        (UnixDatagram::bind(&path).unwrap(), path)
+    }
+
+    fn recv(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[test]
+    fn sends_protocol_messages() {
+        let (socket, path) = stand_in_socket("notify");
+        let mut notifier = SdNotifier::connect(&path).unwrap();
+
+        notifier.ready("Watching 2 directories").unwrap();
+        assert_eq!(recv(&socket), "READY=1\nSTATUS=Watching 2 directories");
+
+        notifier.watchdog_interval = Some(Duration::from_secs(3600));
+        notifier.spawn_watchdog().unwrap();
+        assert_eq!(recv(&socket), "WATCHDOG=1");
+
+        notifier.stopping().unwrap();
+        assert_eq!(
+            recv(&socket),
+            "STOPPING=1\nSTATUS=Flushing pending snapshots"
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+}